@@ -0,0 +1,110 @@
+use crate::{helpers, register, WhiteReference};
+use crate::helpers::{FloatImage, FloatPixel};
+
+/// Shift all channels such that the median of the background equals in all channels.
+///
+/// Pixels with a luminance below the threshold are considered background.
+pub fn neutralize_background<P: FloatPixel>(buf: &mut FloatImage<P>, threshold: f64) {
+    let mut channels = vec![Vec::new(); P::CHANNEL_COUNT as usize];
+    for pixel in buf.pixels() {
+        if helpers::luma(pixel) < threshold {
+            for (channel, &value) in channels.iter_mut().zip(pixel.channels()) {
+                channel.push(value);
+            }
+        }
+    }
+    if channels[0].is_empty() {
+        return;
+    }
+    let medians: Vec<f64> = channels.into_iter().map(|mut channel| {
+        let mid = channel.len() / 2;
+        *channel.select_nth_unstable_by(mid, f64::total_cmp).1
+    }).collect();
+    let target = medians.iter().sum::<f64>() / medians.len() as f64;
+    for pixel in buf.pixels_mut() {
+        for (value, median) in pixel.channels_mut().iter_mut().zip(&medians) {
+            *value -= median - target;
+        }
+    }
+}
+
+/// Scale all channels such that the reference becomes neutral grey
+pub fn white_balance<P: FloatPixel>(buf: &mut FloatImage<P>, reference: WhiteReference) {
+    let sums = match reference {
+        WhiteReference::Region(left, top, width, height) => {
+            let mut sums = vec![0.; P::CHANNEL_COUNT as usize];
+            for y in top..top.saturating_add(height).min(buf.height()) {
+                for x in left..left.saturating_add(width).min(buf.width()) {
+                    for (sum, value) in sums.iter_mut().zip(buf[(x, y)].channels()) {
+                        *sum += value;
+                    }
+                }
+            }
+            sums
+        }
+        WhiteReference::Stars(threshold) => star_sums(buf, threshold),
+    };
+    let target = sums.iter().sum::<f64>() / sums.len() as f64;
+    if sums.iter().any(|&sum| sum <= 0.) {
+        println!("white balance reference is empty, skipping");
+        return;
+    }
+    let factors: Vec<f64> = sums.iter().map(|sum| target / sum).collect();
+    for pixel in buf.pixels_mut() {
+        for (value, factor) in pixel.channels_mut().iter_mut().zip(&factors) {
+            *value *= factor;
+        }
+    }
+}
+
+/// Sum the channels in a small aperture around each unsaturated star brighter than the threshold.
+///
+/// The average star is assumed to be of solar type (G2V), which is white.
+fn star_sums<P: FloatPixel>(buf: &FloatImage<P>, threshold: f64) -> Vec<f64> {
+    const RADIUS: i64 = 2;
+    let width = buf.width() as i64;
+    let height = buf.height() as i64;
+    let luma_at = |x: i64, y: i64| helpers::luma(&buf[(x.max(0).min(width - 1) as u32, y.max(0).min(height - 1) as u32)]);
+    let mut sums = vec![0.; P::CHANNEL_COUNT as usize];
+    for (x, y, pixel) in buf.enumerate_pixels() {
+        let value = helpers::luma(pixel);
+        if value < threshold || pixel.channels().iter().any(|&c| c >= 0.98) {
+            continue;
+        }
+        let (x, y) = (x as i64, y as i64);
+        let local_maximum = (-1..=1).all(|dy| (-1..=1).all(|dx| luma_at(x + dx, y + dy) <= value));
+        if !local_maximum {
+            continue;
+        }
+        for sy in (y - RADIUS).max(0)..=(y + RADIUS).min(height - 1) {
+            for sx in (x - RADIUS).max(0)..=(x + RADIUS).min(width - 1) {
+                for (sum, value) in sums.iter_mut().zip(buf[(sx as u32, sy as u32)].channels()) {
+                    *sum += value;
+                }
+            }
+        }
+    }
+    sums
+}
+
+/// Register the red and blue channel onto the green channel via average brightness alignment
+/// to correct atmospheric dispersion
+pub fn align_channels<P: FloatPixel>(buf: &mut FloatImage<P>, threshold: f64) {
+    let channels = helpers::split_channels(buf);
+    let [red, green, blue]: [_; 3] = match channels.try_into() {
+        Ok(channels) => channels,
+        // nothing to align in monochrome images
+        Err(_) => return,
+    };
+    let centroid = |channel: &helpers::Luma64FImage| register::average_brightness(channel, threshold);
+    let reference = centroid(&green);
+    let align = |channel: helpers::Luma64FImage| {
+        let aba = centroid(&channel);
+        let offset = ((reference.middlex - aba.middlex) as f64, (reference.middley - aba.middley) as f64);
+        println!("channel offset {offset:?}");
+        helpers::shift_subpixel(&channel, offset)
+    };
+    let red = align(red);
+    let blue = align(blue);
+    *buf = helpers::merge_channels(&[red, green, blue]);
+}
@@ -1,4 +1,4 @@
-use image::Rgb;
+use image::Pixel;
 use crate::Colorspace;
 
 fn linear(srgb: f64) -> f64 {
@@ -16,38 +16,26 @@ fn srgb(linear: f64) -> f64 {
     }
 }
 impl Colorspace {
-    pub fn convert_into(&self, px: Rgb<f64>) -> Rgb<f64> {
-        match self {
-            Colorspace::Srgb => px,
-            Colorspace::Linear => {
-                let [r, g, b] = px.0;
-                Rgb::from([linear(r), linear(g), linear(b)])
-            }
-            Colorspace::Quadratic => {
-                let [r, g, b] = px.0;
-                Rgb::from([r.powi(2), g.powi(2), b.powi(2)])
-            }
-            Colorspace::Sqrt => {
-                let [r, g, b] = px.0;
-                Rgb::from([r.sqrt(), g.sqrt(), b.sqrt()])
-            }
+    pub fn convert_into<P: Pixel<Subpixel = f64>>(&self, mut px: P) -> P {
+        for value in px.channels_mut() {
+            *value = match self {
+                Colorspace::Srgb => *value,
+                Colorspace::Linear => linear(*value),
+                Colorspace::Quadratic => value.powi(2),
+                Colorspace::Sqrt => value.sqrt(),
+            };
         }
+        px
     }
-    pub fn convert_back(&self, px: Rgb<f64>) -> Rgb<f64> {
-        match self {
-            Colorspace::Srgb => px,
-            Colorspace::Linear => {
-                let [r, g, b] = px.0;
-                Rgb::from([srgb(r), srgb(g), srgb(b)])
-            }
-            Colorspace::Quadratic => {
-                let [r, g, b] = px.0;
-                Rgb::from([r.sqrt(), g.sqrt(), b.sqrt()])
-            }
-            Colorspace::Sqrt => {
-                let [r, g, b] = px.0;
-                Rgb::from([r.powi(2), g.powi(2), b.powi(2)])
-            }
+    pub fn convert_back<P: Pixel<Subpixel = f64>>(&self, mut px: P) -> P {
+        for value in px.channels_mut() {
+            *value = match self {
+                Colorspace::Srgb => *value,
+                Colorspace::Linear => srgb(*value),
+                Colorspace::Quadratic => value.sqrt(),
+                Colorspace::Sqrt => value.powi(2),
+            };
         }
+        px
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,115 @@
+use image::{Luma, Rgb, Rgb64FImage};
+use crate::{Channel, Combine, CommonArgs, helpers, Mapping, processing, register, RegistrationMethod};
+use crate::helpers::Luma64FImage;
+use crate::register::AkazeRegistration;
+
+pub fn combine(common: CommonArgs, combine: Combine) {
+    let CommonArgs { colorspace, num_files, skip_files: _, mono: _, max_memory: _, prefetch: _ } = common;
+    let Combine {
+        images, mapping, mix_red, mix_green, mix_blue, registration, preprocessing_akaze, preprocessing_rest,
+        akaze, single_object_detection, average_brightness_alignment, disk, processing, outfile,
+    } = combine;
+    assert!(!images.is_empty(), "at least one channel image is required");
+
+    let register = |image: &Luma64FImage, reference: &Luma64FImage| -> (f64, f64) {
+        let preprocess = |image: &Luma64FImage, preprocessing| {
+            let mut image = image.clone();
+            processing::process(&mut image, num_files, preprocessing);
+            image
+        };
+        let akaze = || {
+            let reference = preprocess(reference, &preprocessing_akaze);
+            let image = preprocess(image, &preprocessing_akaze);
+            let reference_data = register::akaze(&reference, akaze);
+            match register::akaze(&image, akaze).akaze_registration(&reference_data, reference.width(), reference.height()) {
+                AkazeRegistration::Offset(dx, dy) | AkazeRegistration::Interpolated(dx, dy) => Some((dx as f64, dy as f64)),
+                AkazeRegistration::Rejected => {
+                    println!("akaze registration rejected");
+                    None
+                }
+            }
+        };
+        let sod = || {
+            let reference = register::single_object_detection(&preprocess(reference, &preprocessing_rest), single_object_detection);
+            let (dx, dy) = register::single_object_detection(&preprocess(image, &preprocessing_rest), single_object_detection).offset(&reference);
+            (dx as f64, dy as f64)
+        };
+        let aba = || {
+            let reference = register::average_brightness(&preprocess(reference, &preprocessing_rest), average_brightness_alignment);
+            let aba = register::average_brightness(&preprocess(image, &preprocessing_rest), average_brightness_alignment);
+            ((reference.middlex - aba.middlex) as f64, (reference.middley - aba.middley) as f64)
+        };
+        let disk = || {
+            let threshold = disk?;
+            let reference = register::disk_fit(reference, threshold)?;
+            let disk = register::disk_fit(image, threshold)?;
+            let (dx, dy) = disk.offset(&reference);
+            Some((dx as f64, dy as f64))
+        };
+        match registration {
+            RegistrationMethod::Akaze => akaze().unwrap_or_else(|| {
+                println!("not aligning channel");
+                (0., 0.)
+            }),
+            RegistrationMethod::Best => akaze().unwrap_or_else(aba),
+            RegistrationMethod::Sod => sod(),
+            RegistrationMethod::Aba => aba(),
+            RegistrationMethod::Disk => disk().unwrap_or_else(|| {
+                println!("no disk found or --disk not given, not aligning channel");
+                (0., 0.)
+            }),
+            // there are no other frames to estimate the noise of the methods or a trajectory from, use the median
+            RegistrationMethod::Fused | RegistrationMethod::Smoothed => {
+                let offsets = [akaze(), Some(sod()), Some(aba()), disk()];
+                let (dx, dy) = register::median_offset(offsets.into_iter().flatten().map(|(dx, dy)| (dx as f32, dy as f32)));
+                (dx as f64, dy as f64)
+            }
+        }
+    };
+
+    // register all channels onto the first one
+    let reference = helpers::load_image::<Luma<f64>, _>(&images[0].1, colorspace);
+    let channels: Vec<(Channel, Luma64FImage)> = images.iter().map(|(channel, path)| {
+        let image = helpers::load_image(path, colorspace);
+        assert_eq!((image.width(), image.height()), (reference.width(), reference.height()), "channel images must have the same size");
+        let offset = register(&image, &reference);
+        println!("{channel:?} offset {offset:?}");
+        (*channel, helpers::shift_subpixel(&image, offset))
+    }).collect();
+    let channel = |wanted: Channel| channels.iter()
+        .find(|(channel, _)| *channel == wanted)
+        .map(|(_, image)| image)
+        .unwrap_or_else(|| panic!("mapping {mapping:?} requires channel {wanted:?}"));
+
+    let (mix_red, mix_green, mix_blue) = match mapping {
+        Mapping::Rgb => (vec![(Channel::R, 1.)], vec![(Channel::G, 1.)], vec![(Channel::B, 1.)]),
+        Mapping::Hoo => (vec![(Channel::Ha, 1.)], vec![(Channel::Oiii, 1.)], vec![(Channel::Oiii, 1.)]),
+        Mapping::Sho => (vec![(Channel::Sii, 1.)], vec![(Channel::Ha, 1.)], vec![(Channel::Oiii, 1.)]),
+        Mapping::Custom => (mix_red, mix_green, mix_blue),
+    };
+    let mix = |weights: &[(Channel, f64)], x: u32, y: u32| {
+        weights.iter().map(|&(c, weight)| weight * channel(c)[(x, y)].0[0]).sum::<f64>()
+    };
+    let mut combined = Rgb64FImage::from_fn(reference.width(), reference.height(), |x, y| {
+        Rgb([mix(&mix_red, x, y), mix(&mix_green, x, y), mix(&mix_blue, x, y)])
+    });
+
+    // LRGB: replace the luminance of the colour image with the luminance channel
+    if channels.iter().any(|(channel, _)| *channel == Channel::L) {
+        let luminance = channel(Channel::L);
+        for (x, y, pixel) in combined.enumerate_pixels_mut() {
+            let current = helpers::luma(pixel);
+            let target = luminance[(x, y)].0[0];
+            if current > 0. {
+                for value in &mut pixel.0 {
+                    *value *= target / current;
+                }
+            } else {
+                *pixel = Rgb([target, target, target]);
+            }
+        }
+    }
+
+    processing::process(&mut combined, num_files, &processing);
+    helpers::save_image(combined, outfile, colorspace);
+}
@@ -11,7 +11,7 @@ use crate::{CommonArgs, Compare, helpers, processing, register};
 use crate::register::{AkazeData, Match, SodRegistration};
 
 pub fn compare(common: CommonArgs, compare: Compare) {
-    let CommonArgs { colorspace, num_files, skip_files: _ } = common;
+    let CommonArgs { colorspace, num_files, skip_files: _, mono: _, max_memory: _, prefetch: _ } = common;
     let Compare { first, second, preprocessing_akaze, preprocessing_rest, akaze, single_object_detection, average_brightness_alignment, outfile_prefix } = compare;
 
     let first = helpers::load_image(first, colorspace);
@@ -115,6 +115,7 @@ fn single_object_detection(left: &Rgb64FImage, right: &Rgb64FImage, threshold: f
         right: o2.right + left.width(),
         top: o2.top,
         bottom: o2.bottom,
+        pixels: o2.pixels,
     };
     helpers::draw_object(res, o1);
     helpers::draw_object(res, o2right);
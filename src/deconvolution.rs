@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use image::Luma;
+use rayon::prelude::*;
+use rustfft::{Fft, FftDirection, FftPlanner};
+use rustfft::num_complex::Complex;
+use crate::{helpers, Psf, register};
+use crate::helpers::{FloatImage, FloatPixel, Luma64FImage};
+
+pub fn richardson_lucy<P: FloatPixel>(buf: &mut FloatImage<P>, iterations: u32, regularization: f64, psf: Psf) {
+    let psf = psf_kernel(buf, psf);
+    let mut channels = helpers::split_channels(buf);
+    channels.par_iter_mut().for_each(|channel| {
+        let radius = psf.width() / 2;
+        let padded = pad(channel, radius);
+        let width = padded.width() as usize;
+        let height = padded.height() as usize;
+        let convolver = Convolver::new(&psf, width, height);
+
+        let observed: Vec<f64> = padded.iter().map(|v| v.max(0.)).collect();
+        let mut estimate = observed.clone();
+        for _ in 0..iterations {
+            let blurred = convolver.convolve(&estimate, false);
+            let ratio: Vec<f64> = observed.iter().zip(&blurred)
+                .map(|(o, b)| o / b.max(f64::EPSILON))
+                .collect();
+            let correction = convolver.convolve(&ratio, true);
+            let tv = if regularization > 0. {
+                total_variation(&estimate, width, height)
+            } else {
+                vec![0.; width * height]
+            };
+            for ((e, c), tv) in estimate.iter_mut().zip(correction).zip(tv) {
+                *e *= c.max(0.) / (1. - regularization * tv).max(0.1);
+            }
+        }
+        *channel = crop(&Luma64FImage::from_raw(width as u32, height as u32, estimate).unwrap(), radius, channel.width(), channel.height());
+    });
+    *buf = helpers::merge_channels(&channels);
+}
+
+/// `noise` is the assumed noise-to-signal power ratio
+pub fn wiener<P: FloatPixel>(buf: &mut FloatImage<P>, noise: f64, psf: Psf) {
+    let psf = psf_kernel(buf, psf);
+    let mut channels = helpers::split_channels(buf);
+    channels.par_iter_mut().for_each(|channel| {
+        let radius = psf.width() / 2;
+        let padded = pad(channel, radius);
+        let width = padded.width() as usize;
+        let height = padded.height() as usize;
+        let convolver = Convolver::new(&psf, width, height);
+
+        let mut spectrum = convolver.forward(&padded);
+        for (s, h) in spectrum.iter_mut().zip(&convolver.otf) {
+            *s = *s * h.conj() / (h.norm_sqr() + noise);
+        }
+        let restored = convolver.inverse(spectrum);
+        *channel = crop(&Luma64FImage::from_raw(width as u32, height as u32, restored).unwrap(), radius, channel.width(), channel.height());
+    });
+    *buf = helpers::merge_channels(&channels);
+}
+
+/// divergence of the normalized gradient
+fn total_variation(data: &[f64], width: usize, height: usize) -> Vec<f64> {
+    let at = |x: usize, y: usize| data[y.min(height - 1) * width + x.min(width - 1)];
+    let normalized_gradient = |x: usize, y: usize| {
+        let gx = at(x + 1, y) - at(x, y);
+        let gy = at(x, y + 1) - at(x, y);
+        let norm = (gx * gx + gy * gy).sqrt().max(1e-8);
+        (gx / norm, gy / norm)
+    };
+    let mut res = vec![0.; width * height];
+    res.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, out) in row.iter_mut().enumerate() {
+            let (nx, ny) = normalized_gradient(x, y);
+            let (nx_left, _) = normalized_gradient(x.saturating_sub(1), y);
+            let (_, ny_top) = normalized_gradient(x, y.saturating_sub(1));
+            *out = (nx - nx_left) + (ny - ny_top);
+        }
+    });
+    res
+}
+
+pub fn psf_kernel<P: FloatPixel>(buf: &FloatImage<P>, psf: Psf) -> Luma64FImage {
+    let kernel = match psf {
+        Psf::Gaussian(sigma) => gaussian(sigma),
+        Psf::Moffat(fwhm, beta) => {
+            let alpha = fwhm / (2. * (2f64.powf(1. / beta) - 1.).sqrt());
+            let radius = (2. * fwhm).ceil().max(1.) as i32;
+            kernel_from_fn(radius, |r| (1. + (r / alpha).powi(2)).powf(-beta))
+        }
+        Psf::Star(x, y, radius) => extract_star(buf, x, y, radius),
+        Psf::Limb(threshold) => gaussian(limb_sigma(buf, threshold)),
+    };
+    normalize(kernel)
+}
+
+fn gaussian(sigma: f64) -> Luma64FImage {
+    let radius = (3. * sigma).ceil().max(1.) as i32;
+    kernel_from_fn(radius, |r| (-r * r / (2. * sigma * sigma)).exp())
+}
+
+fn kernel_from_fn(radius: i32, f: impl Fn(f64) -> f64) -> Luma64FImage {
+    let size = radius as u32 * 2 + 1;
+    Luma64FImage::from_fn(size, size, |x, y| {
+        let dx = x as i32 - radius;
+        let dy = y as i32 - radius;
+        Luma([f(((dx * dx + dy * dy) as f64).sqrt())])
+    })
+}
+
+fn normalize(mut kernel: Luma64FImage) -> Luma64FImage {
+    let sum: f64 = kernel.iter().sum();
+    assert!(sum > 0., "PSF must not be empty");
+    for value in kernel.iter_mut() {
+        *value /= sum;
+    }
+    kernel
+}
+
+/// Cut out the star at the given position and subtract the background at the border of the cutout.
+///
+/// The radius is shrunk to keep the cutout within the image.
+fn extract_star<P: FloatPixel>(buf: &FloatImage<P>, x: u32, y: u32, radius: u32) -> Luma64FImage {
+    assert!(x < buf.width() && y < buf.height(), "star psf position {x}/{y} is outside of the {}x{} image", buf.width(), buf.height());
+    let max_radius = x.min(y).min(buf.width() - 1 - x).min(buf.height() - 1 - y);
+    assert!(max_radius > 0, "star psf position {x}/{y} is at the border of the image");
+    let radius = radius.min(max_radius);
+    let size = radius * 2 + 1;
+    let mut star = Luma64FImage::from_fn(size, size, |dx, dy| {
+        Luma([helpers::luma(&buf[(x + dx - radius, y + dy - radius)])])
+    });
+    let mut border: Vec<f64> = star.enumerate_pixels()
+        .filter(|&(x, y, _)| x == 0 || y == 0 || x == size - 1 || y == size - 1)
+        .map(|(_, _, px)| px.0[0])
+        .collect();
+    let mid = border.len() / 2;
+    let background = *border.select_nth_unstable_by(mid, f64::total_cmp).1;
+    for value in star.iter_mut() {
+        *value = (*value - background).max(0.);
+    }
+    star
+}
+
+/// Estimate the sigma of a gaussian PSF from the line spread function at the limb of the object
+fn limb_sigma<P: FloatPixel>(buf: &FloatImage<P>, threshold: f64) -> f64 {
+    let sod = register::single_object_detection(buf, threshold);
+    let (middlex, middley) = sod.middle();
+    let reach = (sod.width().max(sod.height()) / 10).max(5) as i64;
+    let luma = |x: i64, y: i64| {
+        let x = x.max(0).min(buf.width() as i64 - 1) as u32;
+        let y = y.max(0).min(buf.height() as i64 - 1) as u32;
+        helpers::luma(&buf[(x, y)])
+    };
+
+    // edge position and direction from the outside towards the inside of the object
+    let edges = [
+        (sod.left, middley, 1, 0),
+        (sod.right, middley, -1, 0),
+        (middlex, sod.top, 0, 1),
+        (middlex, sod.bottom, 0, -1),
+    ];
+    let sigmas: Vec<f64> = edges.into_iter().filter_map(|(x, y, dx, dy)| {
+        let profile: Vec<f64> = (-reach..=reach)
+            .map(|i| luma(x as i64 + i * dx, y as i64 + i * dy))
+            .collect();
+        let lsf: Vec<f64> = profile.windows(2).map(|w| (w[1] - w[0]).max(0.)).collect();
+        let sum: f64 = lsf.iter().sum();
+        if sum <= 0. {
+            return None;
+        }
+        let mean = lsf.iter().enumerate().map(|(i, v)| i as f64 * v).sum::<f64>() / sum;
+        let variance = lsf.iter().enumerate().map(|(i, v)| (i as f64 - mean).powi(2) * v).sum::<f64>() / sum;
+        Some(variance.sqrt())
+    }).collect();
+    if sigmas.is_empty() {
+        return 1.;
+    }
+    (sigmas.iter().sum::<f64>() / sigmas.len() as f64).max(0.5)
+}
+
+fn pad(channel: &Luma64FImage, radius: u32) -> Luma64FImage {
+    let width = channel.width();
+    let height = channel.height();
+    Luma64FImage::from_fn(width + 2 * radius, height + 2 * radius, |x, y| {
+        let x = (x as i64 - radius as i64).max(0).min(width as i64 - 1) as u32;
+        let y = (y as i64 - radius as i64).max(0).min(height as i64 - 1) as u32;
+        channel[(x, y)]
+    })
+}
+
+fn crop(channel: &Luma64FImage, radius: u32, width: u32, height: u32) -> Luma64FImage {
+    Luma64FImage::from_fn(width, height, |x, y| channel[(x + radius, y + radius)])
+}
+
+/// Circular convolution with a fixed kernel via FFT
+struct Convolver {
+    width: usize,
+    height: usize,
+    /// optical transfer function, i.e. the spectrum of the PSF centered at the origin
+    otf: Vec<Complex<f64>>,
+    rows: [Arc<dyn Fft<f64>>; 2],
+    columns: [Arc<dyn Fft<f64>>; 2],
+}
+
+impl Convolver {
+    fn new(psf: &Luma64FImage, width: usize, height: usize) -> Convolver {
+        let mut planner = FftPlanner::new();
+        let rows = [planner.plan_fft(width, FftDirection::Forward), planner.plan_fft(width, FftDirection::Inverse)];
+        let columns = [planner.plan_fft(height, FftDirection::Forward), planner.plan_fft(height, FftDirection::Inverse)];
+        let mut convolver = Convolver { width, height, otf: Vec::new(), rows, columns };
+
+        let radius = psf.width() as usize / 2;
+        let mut centered = vec![0.; width * height];
+        for (x, y, px) in psf.enumerate_pixels() {
+            let x = (x as usize + width - radius) % width;
+            let y = (y as usize + height - radius) % height;
+            centered[y * width + x] += px.0[0];
+        }
+        convolver.otf = convolver.forward(&centered);
+        convolver
+    }
+
+    fn convolve(&self, data: &[f64], conjugate: bool) -> Vec<f64> {
+        let mut spectrum = self.forward(data);
+        for (s, h) in spectrum.iter_mut().zip(&self.otf) {
+            *s *= if conjugate { h.conj() } else { *h };
+        }
+        self.inverse(spectrum)
+    }
+
+    fn forward(&self, data: &[f64]) -> Vec<Complex<f64>> {
+        let mut spectrum: Vec<_> = data.iter().map(|&v| Complex::new(v, 0.)).collect();
+        self.fft2d(&mut spectrum, 0);
+        spectrum
+    }
+
+    fn inverse(&self, mut spectrum: Vec<Complex<f64>>) -> Vec<f64> {
+        self.fft2d(&mut spectrum, 1);
+        let scale = (self.width * self.height) as f64;
+        spectrum.into_iter().map(|c| c.re / scale).collect()
+    }
+
+    fn fft2d(&self, data: &mut Vec<Complex<f64>>, direction: usize) {
+        self.rows[direction].process(data);
+        let mut transposed = transpose(data, self.width, self.height);
+        self.columns[direction].process(&mut transposed);
+        *data = transpose(&transposed, self.height, self.width);
+    }
+}
+
+fn transpose(data: &[Complex<f64>], width: usize, height: usize) -> Vec<Complex<f64>> {
+    let mut res = vec![Complex::new(0., 0.); width * height];
+    for y in 0..height {
+        for x in 0..width {
+            res[x * height + y] = data[y * width + x];
+        }
+    }
+    res
+}
@@ -0,0 +1,121 @@
+use image::{ImageBuffer, Luma};
+use rayon::prelude::*;
+use crate::helpers::{self, FloatImage, FloatPixel, Luma64FImage};
+use crate::wavelets;
+
+/// Edge-preserving gaussian blur, where neighbours are additionally weighted by their difference in value
+pub fn bilateral<P: FloatPixel>(buf: &mut FloatImage<P>, sigma_spatial: f32, sigma_luma: f64, sigma_chroma: f64) {
+    luma_chroma(buf, |plane, sigma_range| bilateral_plane(plane, sigma_spatial, sigma_range), sigma_luma, sigma_chroma);
+}
+
+/// Non-local means with the given filter strengths, search-window radius and patch radius
+pub fn non_local_means<P: FloatPixel>(buf: &mut FloatImage<P>, h_luma: f64, h_chroma: f64, search_radius: u32, patch_radius: u32) {
+    luma_chroma(buf, |plane, h| non_local_means_plane(plane, h, search_radius as i64, patch_radius as i64), h_luma, h_chroma);
+}
+
+/// Soft-threshold the first `layers` wavelet layers by the given multiples of their estimated noise
+pub fn wavelet<P: FloatPixel>(buf: &mut FloatImage<P>, threshold_luma: f64, threshold_chroma: f64, layers: usize) {
+    luma_chroma(buf, |plane, threshold| wavelet_plane(plane, threshold, layers), threshold_luma, threshold_chroma);
+}
+
+/// Split the image into luminance and chrominance planes, denoise them separately with
+/// their respective strength and combine them again.
+fn luma_chroma<P: FloatPixel>(buf: &mut FloatImage<P>, denoise: impl Fn(&Luma64FImage, f64) -> Luma64FImage + Sync, luma_strength: f64, chroma_strength: f64) {
+    let width = buf.width();
+    let height = buf.height();
+    let luma = Luma64FImage::from_fn(width, height, |x, y| Luma([helpers::luma(&buf[(x, y)])]));
+    let mut planes = vec![(luma.clone(), luma_strength)];
+    // monochrome images have no chrominance
+    if P::CHANNEL_COUNT > 1 {
+        planes.extend(helpers::split_channels(buf).into_iter().map(|mut channel| {
+            for (value, l) in channel.iter_mut().zip(luma.iter()) {
+                *value -= l;
+            }
+            (channel, chroma_strength)
+        }));
+    }
+    let denoised: Vec<_> = planes.par_iter()
+        .map(|(plane, strength)| if *strength > 0. { denoise(plane, *strength) } else { plane.clone() })
+        .collect();
+    let (luma, chroma) = denoised.split_first().unwrap();
+    for (x, y, pixel) in buf.enumerate_pixels_mut() {
+        let l = luma[(x, y)].0[0];
+        if chroma.is_empty() {
+            pixel.channels_mut()[0] = l;
+        }
+        for (value, chroma) in pixel.channels_mut().iter_mut().zip(chroma) {
+            *value = l + chroma[(x, y)].0[0];
+        }
+    }
+}
+
+fn bilateral_plane(plane: &Luma64FImage, sigma_spatial: f32, sigma_range: f64) -> Luma64FImage {
+    let width = plane.width() as i64;
+    let height = plane.height() as i64;
+    let radius = (2. * sigma_spatial).ceil().max(1.) as i64;
+    let spatial: Vec<f64> = (-radius..=radius)
+        .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+        .map(|(dx, dy)| (-((dx * dx + dy * dy) as f64) / (2. * sigma_spatial as f64 * sigma_spatial as f64)).exp())
+        .collect();
+    let at = |x: i64, y: i64| plane.as_raw()[(y.max(0).min(height - 1) * width + x.max(0).min(width - 1)) as usize];
+
+    let mut res = vec![0.; (width * height) as usize];
+    res.par_chunks_mut(width as usize).enumerate().for_each(|(y, row)| {
+        let y = y as i64;
+        for (x, out) in row.iter_mut().enumerate() {
+            let x = x as i64;
+            let center = at(x, y);
+            let mut sum = 0.;
+            let mut weights = 0.;
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let value = at(x + dx, y + dy);
+                    let index = ((dy + radius) * (2 * radius + 1) + dx + radius) as usize;
+                    let weight = spatial[index] * (-(value - center).powi(2) / (2. * sigma_range * sigma_range)).exp();
+                    sum += weight * value;
+                    weights += weight;
+                }
+            }
+            *out = sum / weights;
+        }
+    });
+    ImageBuffer::from_raw(width as u32, height as u32, res).unwrap()
+}
+
+fn non_local_means_plane(plane: &Luma64FImage, h: f64, search_radius: i64, patch_radius: i64) -> Luma64FImage {
+    let width = plane.width() as i64;
+    let height = plane.height() as i64;
+    let at = |x: i64, y: i64| plane.as_raw()[(y.max(0).min(height - 1) * width + x.max(0).min(width - 1)) as usize];
+    let patch_size = ((2 * patch_radius + 1) * (2 * patch_radius + 1)) as f64;
+
+    let mut res = vec![0.; (width * height) as usize];
+    res.par_chunks_mut(width as usize).enumerate().for_each(|(y, row)| {
+        let y = y as i64;
+        for (x, out) in row.iter_mut().enumerate() {
+            let x = x as i64;
+            let mut sum = 0.;
+            let mut weights = 0.;
+            for sy in -search_radius..=search_radius {
+                for sx in -search_radius..=search_radius {
+                    let mut distance = 0.;
+                    for py in -patch_radius..=patch_radius {
+                        for px in -patch_radius..=patch_radius {
+                            distance += (at(x + px, y + py) - at(x + sx + px, y + sy + py)).powi(2);
+                        }
+                    }
+                    let weight = (-distance / patch_size / (h * h)).exp();
+                    sum += weight * at(x + sx, y + sy);
+                    weights += weight;
+                }
+            }
+            *out = sum / weights;
+        }
+    });
+    ImageBuffer::from_raw(width as u32, height as u32, res).unwrap()
+}
+
+fn wavelet_plane(plane: &Luma64FImage, threshold: f64, layers: usize) -> Luma64FImage {
+    let mut plane = plane.clone();
+    wavelets::sharpen_channel(&mut plane, &vec![(1., threshold); layers]);
+    plane
+}
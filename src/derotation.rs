@@ -0,0 +1,110 @@
+use std::f64::consts::PI;
+use crate::{Planet, source};
+use crate::helpers::{FloatImage, FloatPixel};
+use crate::register::ImageRegistration;
+
+/// Warps the planetary disk of frames onto the rotation of the planet at a common epoch.
+///
+/// The disk is the circle fitted to the limb, or the object found by single object detection for frames
+/// without disk fit, modelled as oblate spheroid seen equator-on. Images are expected unmirrored, i.e.
+/// with east left when north is up, such that features move to the right over time.
+#[derive(Debug, Copy, Clone)]
+pub struct Derotation {
+    planet: Planet,
+    /// angle of the north pole from the top of the image in radians, counter-clockwise
+    pole_angle: f64,
+    /// time to derotate onto in seconds since the Unix epoch
+    epoch: f64,
+}
+
+impl Derotation {
+    /// `pole_angle` in degrees, `epoch` defaults to the middle of the session.
+    ///
+    /// `None` if no frame has a timestamp.
+    pub fn new(planet: Planet, pole_angle: f64, epoch: Option<f64>, images: &[ImageRegistration]) -> Option<Derotation> {
+        let timestamps: Vec<f64> = images.iter().filter_map(timestamp).collect();
+        if timestamps.is_empty() {
+            println!("no frame has a timestamp, not derotating");
+            return None;
+        }
+        if timestamps.len() < images.len() {
+            println!("{} frames have no timestamp and won't be derotated", images.len() - timestamps.len());
+        }
+        let first = timestamps.iter().copied().fold(f64::INFINITY, f64::min);
+        let last = timestamps.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let epoch = epoch.unwrap_or((first + last) / 2.);
+        let max_rotation = (first - epoch).abs().max((last - epoch).abs()) / planet.rotation_period() * 360.;
+        println!("derotating {:.1} minutes of {planet:?} onto {epoch:.0}, rotating frames by up to {max_rotation:.1}°", (last - first) / 60.);
+        Some(Derotation { planet, pole_angle: pole_angle.to_radians(), epoch })
+    }
+
+    pub fn derotate<P: FloatPixel>(&self, image: &FloatImage<P>, reg: &ImageRegistration) -> FloatImage<P> {
+        let Some(timestamp) = timestamp(reg) else {
+            return image.clone();
+        };
+        // longitude the planet rotated since the epoch
+        let rotation = 2. * PI * (timestamp - self.epoch) / self.planet.rotation_period();
+        // the fitted limb is more precise than the detection box, which is widened by the seeing
+        let (middlex, middley, equator) = match reg.disk {
+            Some(disk) => (disk.middlex as f64, disk.middley as f64, disk.radius as f64),
+            None => {
+                let (middlex, middley) = reg.sod.middle();
+                (middlex as f64, middley as f64, reg.sod.width().max(reg.sod.height()) as f64 / 2.)
+            }
+        };
+        let pole = equator * (1. - self.planet.flattening());
+        if equator < 1. {
+            return image.clone();
+        }
+        let (sin, cos) = self.pole_angle.sin_cos();
+
+        let mut derotated = image.clone();
+        for (x, y, pixel) in derotated.enumerate_pixels_mut() {
+            // coordinates on the disk with the equator horizontal, normalized to the radii
+            let dx = x as f64 - middlex;
+            let dy = y as f64 - middley;
+            let diskx = (dx * cos - dy * sin) / equator;
+            let disky = (dx * sin + dy * cos) / pole;
+            // radius of the circle of latitude
+            let latitude_radius = (1. - disky * disky).max(0.).sqrt();
+            if diskx.abs() >= latitude_radius {
+                continue;
+            }
+            let longitude = (diskx / latitude_radius).asin();
+            // the surface at this longitude at the epoch was at `longitude + rotation` in the frame;
+            // keep the frame's pixel if it was on the far side
+            let source_longitude = longitude + rotation;
+            if source_longitude.abs() >= PI / 2. {
+                continue;
+            }
+            let diskx = latitude_radius * source_longitude.sin() * equator;
+            let disky = disky * pole;
+            let sourcex = middlex + diskx * cos + disky * sin;
+            let sourcey = middley - diskx * sin + disky * cos;
+            *pixel = sample(image, sourcex, sourcey);
+        }
+        derotated
+    }
+}
+
+/// Capture time of the frame, from the registration or its file for older registrations
+fn timestamp(reg: &ImageRegistration) -> Option<f64> {
+    reg.timestamp.or_else(|| source::timestamp(&reg.image))
+}
+
+/// Bilinear interpolation of the image at a fractional position
+fn sample<P: FloatPixel>(image: &FloatImage<P>, x: f64, y: f64) -> P {
+    let x = x.clamp(0., (image.width() - 1) as f64);
+    let y = y.clamp(0., (image.height() - 1) as f64);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(image.width() - 1), (y0 + 1).min(image.height() - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let mut result = *image.get_pixel(x0, y0);
+    let neighbours = [(x0, y0, (1. - fx) * (1. - fy)), (x1, y0, fx * (1. - fy)), (x0, y1, (1. - fx) * fy), (x1, y1, fx * fy)];
+    for (channel, value) in result.channels_mut().iter_mut().enumerate() {
+        *value = neighbours.iter()
+            .map(|&(nx, ny, weight)| weight * image.get_pixel(nx, ny).channels()[channel])
+            .sum();
+    }
+    result
+}
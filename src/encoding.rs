@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use image::EncodableLayout;
+use minimp4::Mp4Muxer;
+use openh264::encoder::{Encoder, EncoderConfig};
+use openh264::formats::RBGYUVConverter;
+use crate::helpers::{FloatImage, FloatPixel};
+
+/// Output format of a video, selected by the extension of the output file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// H.264 in an MP4 container
+    Mp4,
+    /// animated GIF with a 256 colour palette per frame
+    Gif,
+    /// animated PNG, lossless with 8 or 16 bit
+    Apng,
+    /// uncompressed YUV 4:4:4 with 8 or 16 bit, readable by ffmpeg and most video tools
+    Y4m,
+}
+
+impl VideoFormat {
+    pub fn from_extension(extension: &str) -> Option<VideoFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "mp4" => Some(VideoFormat::Mp4),
+            "gif" => Some(VideoFormat::Gif),
+            "png" | "apng" => Some(VideoFormat::Apng),
+            "y4m" => Some(VideoFormat::Y4m),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            VideoFormat::Mp4 => "mp4",
+            VideoFormat::Gif => "gif",
+            VideoFormat::Apng => "png",
+            VideoFormat::Y4m => "y4m",
+        }
+    }
+
+    /// Split `path` into the format given by its extension, defaulting to MP4, and the path without extension
+    pub fn from_path(path: &Path) -> (VideoFormat, PathBuf) {
+        let format = path.extension()
+            .and_then(|extension| VideoFormat::from_extension(extension.to_str()?));
+        match format {
+            Some(format) => (format, path.with_extension("")),
+            None => (VideoFormat::Mp4, path.to_owned()),
+        }
+    }
+}
+
+/// Writes frames of the same size into a video file of a [`VideoFormat`]
+pub enum VideoWriter {
+    /// H.264 is buffered and muxed into the MP4 container when finishing
+    Mp4 { encoder: Encoder, buf: Vec<u8>, path: PathBuf, width: u32, height: u32, fps: u32 },
+    /// with the frame delay in units of 10 ms
+    Gif(gif::Encoder<BufWriter<File>>, u16),
+    /// with 16 bit samples if the bool is set
+    Apng(png::Writer<BufWriter<File>>, bool),
+    Y4m(BufWriter<File>, bool),
+}
+
+impl VideoWriter {
+    /// `bitrate` in kbit/s only applies to MP4, `high_bit_depth` to APNG and Y4M,
+    /// `num_frames` must be known beforehand for APNG
+    pub fn new(format: VideoFormat, path: PathBuf, (width, height): (u32, u32), fps: u32, bitrate: Option<u32>, high_bit_depth: bool, num_frames: u32) -> VideoWriter {
+        match format {
+            VideoFormat::Mp4 => {
+                let mut config = EncoderConfig::new(width, height).max_frame_rate(fps as f32);
+                if let Some(bitrate) = bitrate {
+                    config = config.set_bitrate_bps(bitrate * 1000);
+                }
+                let encoder = Encoder::with_config(config).unwrap();
+                VideoWriter::Mp4 { encoder, buf: Vec::new(), path, width, height, fps }
+            }
+            VideoFormat::Gif => {
+                let file = BufWriter::new(File::create(path).unwrap());
+                let size = |length: u32| u16::try_from(length).expect("GIF frames can't be larger than 65535 pixels");
+                let mut encoder = gif::Encoder::new(file, size(width), size(height), &[]).unwrap();
+                encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+                VideoWriter::Gif(encoder, (100. / fps as f64).round().max(1.) as u16)
+            }
+            VideoFormat::Apng => {
+                let file = BufWriter::new(File::create(path).unwrap());
+                let mut encoder = png::Encoder::new(file, width, height);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(if high_bit_depth { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+                encoder.set_animated(num_frames, 0).unwrap();
+                encoder.set_frame_delay(1, fps as u16).unwrap();
+                VideoWriter::Apng(encoder.write_header().unwrap(), high_bit_depth)
+            }
+            VideoFormat::Y4m => {
+                let mut file = BufWriter::new(File::create(path).unwrap());
+                let colorspace = if high_bit_depth { "C444p16" } else { "C444" };
+                writeln!(file, "YUV4MPEG2 W{width} H{height} F{fps}:1 Ip A1:1 {colorspace}").unwrap();
+                VideoWriter::Y4m(file, high_bit_depth)
+            }
+        }
+    }
+
+    pub fn write<P: FloatPixel>(&mut self, frame: &FloatImage<P>) {
+        match self {
+            VideoWriter::Mp4 { encoder, buf, width, height, .. } => {
+                let mut yuv = RBGYUVConverter::new(*width as usize, *height as usize);
+                yuv.convert(P::to_rgb8(frame).as_bytes());
+                // Encode YUV into H.264.
+                let bitstream = encoder.encode(&yuv).unwrap();
+                bitstream.write_vec(buf);
+            }
+            VideoWriter::Gif(encoder, delay) => {
+                let rgb = P::to_rgb8(frame);
+                // the default speed of 1 quantizes the palette from every pixel, which is very slow for large frames
+                let mut frame = gif::Frame::from_rgb_speed(rgb.width() as u16, rgb.height() as u16, &rgb, 10);
+                frame.delay = *delay;
+                encoder.write_frame(&frame).unwrap();
+            }
+            VideoWriter::Apng(writer, false) => writer.write_image_data(P::to_rgb8(frame).as_raw()).unwrap(),
+            VideoWriter::Apng(writer, true) => {
+                let data: Vec<u8> = frame.pixels()
+                    .flat_map(|pixel| pixel.to_rgb().0)
+                    .flat_map(|value| ((value.clamp(0., 1.) * 65535.).round() as u16).to_be_bytes())
+                    .collect();
+                writer.write_image_data(&data).unwrap();
+            }
+            VideoWriter::Y4m(file, high_bit_depth) => {
+                file.write_all(b"FRAME\n").unwrap();
+                for plane in yuv444(frame) {
+                    let data: Vec<u8> = if *high_bit_depth {
+                        plane.into_iter().flat_map(|value| ((value * 256.).round() as u16).to_le_bytes()).collect()
+                    } else {
+                        plane.into_iter().map(|value| value.round() as u8).collect()
+                    };
+                    file.write_all(&data).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Write the trailer and flush the file
+    pub fn finish(self) -> Result<(), String> {
+        match self {
+            VideoWriter::Mp4 { buf, path, width, height, fps, .. } => {
+                let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+                let file = File::create(&path).unwrap();
+                let mut mp4muxer = Mp4Muxer::new(file);
+                mp4muxer.init_video(width as i32, height as i32, false, &name);
+                mp4muxer.write_video_with_fps(&buf, fps);
+                mp4muxer.close();
+                Ok(())
+            }
+            VideoWriter::Gif(encoder, _) => encoder.into_inner().and_then(|mut file| file.flush()).map_err(|e| e.to_string()),
+            VideoWriter::Apng(writer, _) => writer.finish().map_err(|e| e.to_string()),
+            VideoWriter::Y4m(mut file, _) => file.flush().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Y, Cb and Cr planes of the frame in limited range BT.601 like the H.264 encoding, scaled to 8 bit
+fn yuv444<P: FloatPixel>(frame: &FloatImage<P>) -> [Vec<f64>; 3] {
+    let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+    for pixel in frame.pixels() {
+        let [r, g, b] = pixel.to_rgb().0.map(|c| c.clamp(0., 1.));
+        let y = 16. + 65.481 * r + 128.553 * g + 24.966 * b;
+        let cb = 128. - 37.797 * r - 74.203 * g + 112.0 * b;
+        let cr = 128. + 112.0 * r - 93.786 * g - 18.214 * b;
+        for (plane, value) in planes.iter_mut().zip([y, cb, cr]) {
+            plane.push(value);
+        }
+    }
+    planes
+}
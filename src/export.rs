@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use image::{DynamicImage, imageops, Luma, Rgb};
+use crate::{Colorspace, CommonArgs, Export, helpers, processing, Processing, rejection};
+use crate::helpers::{FloatImage, FloatPixel};
+use crate::loader::Loader;
+use crate::register::ImageRegistration;
+
+/// Size of a FITS header or data block
+const FITS_BLOCK: usize = 2880;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ExportFormat {
+    Png,
+    Tiff,
+    Fits,
+    Ser,
+}
+
+pub fn export(common: CommonArgs, export: Export) {
+    if common.mono {
+        export_generic::<Luma<f64>>(common, export)
+    } else {
+        export_generic::<Rgb<f64>>(common, export)
+    }
+}
+
+fn export_generic<P: FloatPixel>(common: CommonArgs, export: Export) {
+    let CommonArgs { colorspace, num_files, skip_files, mono: _, max_memory: _, prefetch: _ } = common;
+    let Export { registration_input, rejection, rejection_policy, processing, method, subpixel, crop, outfile } = export;
+
+    let extension = outfile.extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    let format = match extension.as_str() {
+        "png" => ExportFormat::Png,
+        "tif" | "tiff" => ExportFormat::Tiff,
+        "fit" | "fits" | "fts" => ExportFormat::Fits,
+        "ser" => ExportFormat::Ser,
+        _ => panic!("unsupported export format {:?}, use .png, .tif, .fits or .ser", outfile),
+    };
+
+    let registration = helpers::load_registration(registration_input);
+    let reference = &registration.images[registration.reference_image];
+    let reference_image = helpers::load_image::<P, _>(&reference.image, colorspace);
+    let width = reference_image.width();
+    let height = reference_image.height();
+
+    let images = helpers::clamp_slice(&registration.images, skip_files, num_files);
+    let images = rejection::reject(&registration, images.to_owned(), &rejection, rejection_policy);
+    let count = images.len();
+    let images: Vec<_> = images.into_iter()
+        .filter(|reg| reg.offset(reference, method).is_some())
+        .collect();
+    if images.len() < count {
+        println!("skipping {} frames without {} registration", count - images.len(), method.name());
+    }
+
+    // replace maxscale with maxscale_fixed based on first image
+    let processing: Vec<_> = processing.into_iter()
+        .map(|processing| match processing {
+            Processing::Maxscale => Processing::MaxscaleFixed(processing::maxcol(&reference_image)),
+            p => p,
+        }).collect();
+
+    let (left, top, crop_width, crop_height) = helpers::crop_region(crop, reference, width, height);
+    let mut ser = (format == ExportFormat::Ser)
+        .then(|| SerWriter::new(&outfile, crop_width, crop_height, P::CHANNEL_COUNT, images.len()));
+    let prefix = outfile.with_extension("");
+
+    // only the samples of SER frames are passed on to be written in order, the other formats are written by the workers
+    let loader = Loader::new_ordered::<P>(&common, width, height, 2, (format == ExportFormat::Ser) as usize, 0);
+    let images: Vec<_> = images.into_iter().enumerate().collect();
+    let process_frame = |(i, reg): (usize, ImageRegistration), mut image: FloatImage<P>| {
+        processing::process(&mut image, num_files, &processing);
+        let offset = if subpixel {
+            let (dx, dy) = reg.subpixel_offset(reference, method).unwrap();
+            (dx as f64, dy as f64)
+        } else {
+            let (dx, dy) = reg.offset(reference, method).unwrap();
+            (dx as f64, dy as f64)
+        };
+        let image = helpers::shift_subpixel(&image, offset);
+        let image = imageops::crop_imm(&image, left, top, crop_width, crop_height).to_image();
+
+        let path = helpers::path_with_suffix(&prefix, &format!("{i:05}.{extension}"));
+        match format {
+            ExportFormat::Png | ExportFormat::Tiff => helpers::save_image(image, path, colorspace),
+            ExportFormat::Fits => write_fits(&path, crop_width, crop_height, P::CHANNEL_COUNT, &samples16(image, colorspace)),
+            ExportFormat::Ser => return Some(samples16(image, colorspace)),
+        }
+        None
+    };
+    let mut count = 0;
+    loader.par_map_ordered(images, |(_, reg)| &reg.image, process_frame, |samples| {
+        if count % 50 == 0 {
+            println!("{count}");
+        }
+        count += 1;
+        if let Some(samples) = samples {
+            ser.as_mut().unwrap().write(&samples);
+        }
+    });
+
+    if let Some(ser) = ser {
+        ser.finish();
+    }
+}
+
+/// Interleaved 16 bit samples of the image converted back from the colorspace
+fn samples16<P: FloatPixel>(mut image: FloatImage<P>, colorspace: Colorspace) -> Vec<u16> {
+    for pixel in image.pixels_mut() {
+        *pixel = colorspace.convert_back(*pixel);
+    }
+    match P::into_dynamic16(image) {
+        DynamicImage::ImageRgb16(image) => image.into_raw(),
+        DynamicImage::ImageLuma16(image) => image.into_raw(),
+        _ => unreachable!("16 bit images are either rgb or luma"),
+    }
+}
+
+/// Write the interleaved samples as 16 bit FITS image with one plane per channel
+fn write_fits(path: &Path, width: u32, height: u32, channels: u8, samples: &[u16]) {
+    let mut header = String::new();
+    let mut card = |key: &str, value: &str| header.push_str(&format!("{key:<8}= {value:>20}{:50}", ""));
+    card("SIMPLE", "T");
+    card("BITPIX", "16");
+    card("NAXIS", if channels == 1 { "2" } else { "3" });
+    card("NAXIS1", &width.to_string());
+    card("NAXIS2", &height.to_string());
+    if channels > 1 {
+        card("NAXIS3", &channels.to_string());
+    }
+    // FITS only has signed integers, unsigned values are stored with an offset
+    card("BZERO", "32768");
+    card("BSCALE", "1");
+    header.push_str(&format!("{:80}", "END"));
+
+    let mut data = header.into_bytes();
+    data.resize(data.len().next_multiple_of(FITS_BLOCK), b' ');
+    let header_len = data.len();
+    let channels = channels as usize;
+    let width = width as usize;
+    for channel in 0..channels {
+        // the first row of a FITS image is the bottom one
+        for row in samples.chunks(width * channels).rev() {
+            for value in row.iter().skip(channel).step_by(channels) {
+                data.extend_from_slice(&((*value as i32 - 32768) as i16).to_be_bytes());
+            }
+        }
+    }
+    data.resize(header_len + (data.len() - header_len).next_multiple_of(FITS_BLOCK), 0);
+    File::create(path).unwrap().write_all(&data).unwrap();
+}
+
+/// Writer of a SER video with 16 bit samples
+struct SerWriter {
+    file: BufWriter<File>,
+}
+
+impl SerWriter {
+    fn new(path: &Path, width: u32, height: u32, channels: u8, frames: usize) -> SerWriter {
+        let mut file = BufWriter::new(File::create(path).unwrap());
+        let color_id: i32 = if channels == 1 { 0 } else { 100 };
+        let mut header = Vec::new();
+        header.extend_from_slice(b"LUCAM-RECORDER");
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&color_id.to_le_bytes());
+        // capture software writes little endian data with 0 despite the spec, which is what readers expect
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&(width as i32).to_le_bytes());
+        header.extend_from_slice(&(height as i32).to_le_bytes());
+        header.extend_from_slice(&16i32.to_le_bytes());
+        header.extend_from_slice(&(frames as i32).to_le_bytes());
+        // observer, instrument and telescope
+        header.extend_from_slice(&[b' '; 120]);
+        // local and UTC date time of the start of the capture
+        header.extend_from_slice(&0i64.to_le_bytes());
+        header.extend_from_slice(&0i64.to_le_bytes());
+        file.write_all(&header).unwrap();
+        SerWriter { file }
+    }
+
+    fn write(&mut self, samples: &[u16]) {
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.file.write_all(&bytes).unwrap();
+    }
+
+    fn finish(mut self) {
+        self.file.flush().unwrap();
+    }
+}
@@ -1,23 +1,80 @@
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use cv::feature::akaze::KeyPoint;
-use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Luma, Rgb, Rgb64FImage, RgbImage};
-use image::io::Reader;
-use crate::Colorspace;
-use crate::register::{Registration, SodRegistration};
+use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Luma, Pixel, Rgb, Rgb64FImage, RgbImage};
+use image::buffer::ConvertBuffer;
+use crate::{Colorspace, Crop, source};
+use crate::register::{DiskRegistration, ImageRegistration, Registration, SodRegistration};
 
-pub fn load_image<P: AsRef<Path>>(path: P, colorspace: Colorspace) -> Rgb64FImage {
-    let mut img = Reader::open(path).unwrap().decode().unwrap().into_rgb64f();
+/// A single colour channel of a `FloatImage`
+pub type Luma64FImage = ImageBuffer<Luma<f64>, Vec<f64>>;
+pub type FloatImage<P> = ImageBuffer<P, Vec<f64>>;
+
+/// Pixel types the pipeline works on: `Rgb<f64>` for colour and `Luma<f64>` for monochrome data
+pub trait FloatPixel: Pixel<Subpixel = f64> + Send + Sync + 'static {
+    /// colour used to draw markers into the image
+    const MARKER: Self;
+    fn from_dynamic(image: DynamicImage) -> FloatImage<Self>;
+    fn to_dynamic(image: &FloatImage<Self>) -> DynamicImage;
+    /// 16 bit image used for saving
+    fn into_dynamic16(image: FloatImage<Self>) -> DynamicImage;
+    fn to_rgb8(image: &FloatImage<Self>) -> RgbImage;
+    fn to_luma16(image: &FloatImage<Self>) -> ImageBuffer<Luma<u16>, Vec<u16>>;
+}
+impl FloatPixel for Rgb<f64> {
+    const MARKER: Self = Rgb([1., 0., 0.]);
+    fn from_dynamic(image: DynamicImage) -> Rgb64FImage {
+        image.into_rgb64f()
+    }
+    fn to_dynamic(image: &Rgb64FImage) -> DynamicImage {
+        DynamicImage::ImageRgb64F(image.clone())
+    }
+    fn into_dynamic16(image: Rgb64FImage) -> DynamicImage {
+        DynamicImage::ImageRgb16(DynamicImage::ImageRgb64F(image).into_rgb16())
+    }
+    fn to_rgb8(image: &Rgb64FImage) -> RgbImage {
+        image.convert()
+    }
+    fn to_luma16(image: &Rgb64FImage) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        image.convert()
+    }
+}
+impl FloatPixel for Luma<f64> {
+    const MARKER: Self = Luma([1.]);
+    fn from_dynamic(image: DynamicImage) -> Luma64FImage {
+        image.into_luma16().convert()
+    }
+    fn to_dynamic(image: &Luma64FImage) -> DynamicImage {
+        DynamicImage::ImageLuma16(image.convert())
+    }
+    fn into_dynamic16(image: Luma64FImage) -> DynamicImage {
+        DynamicImage::ImageLuma16(image.convert())
+    }
+    fn to_rgb8(image: &Luma64FImage) -> RgbImage {
+        DynamicImage::ImageLuma16(image.convert()).into_rgb8()
+    }
+    fn to_luma16(image: &Luma64FImage) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        image.convert()
+    }
+}
+
+/// average of all channels of the pixel
+pub fn luma<P: Pixel<Subpixel = f64>>(pixel: &P) -> f64 {
+    pixel.channels().iter().sum::<f64>() / P::CHANNEL_COUNT as f64
+}
+
+pub fn load_image<P: FloatPixel, Q: AsRef<Path>>(path: Q, colorspace: Colorspace) -> FloatImage<P> {
+    let mut img = P::from_dynamic(source::load(path.as_ref()));
     for px in img.pixels_mut() {
         *px = colorspace.convert_into(*px);
     }
     img
 }
-pub fn save_image<P: AsRef<Path>>(mut img: Rgb64FImage, path: P, colorspace: Colorspace) {
+pub fn save_image<P: FloatPixel, Q: AsRef<Path>>(mut img: FloatImage<P>, path: Q, colorspace: Colorspace) {
     for pixel in img.pixels_mut() {
         *pixel = colorspace.convert_back(*pixel);
     }
-    DynamicImage::ImageRgb64F(img).into_rgb16().save(path).unwrap();
+    P::into_dynamic16(img).save(path).unwrap();
 }
 
 pub fn load_registration<P: AsRef<Path>>(path: P) -> Registration {
@@ -40,10 +97,10 @@ pub fn clamp_slice<T>(mut slice: &[T], skip: usize, len: usize) -> &[T] {
     &slice[..len]
 }
 
-pub fn offset_image(image: &RgbImage, (dx, dy): (i32, i32)) -> RgbImage {
+pub fn offset_image<P: Pixel>(image: &ImageBuffer<P, Vec<P::Subpixel>>, (dx, dy): (i32, i32)) -> ImageBuffer<P, Vec<P::Subpixel>> {
     let width = image.width();
     let height = image.height();
-    let mut frame = RgbImage::new(width, height);
+    let mut frame = ImageBuffer::new(width, height);
     let sourcex = (-dx).max(0) as u32;
     let sourcey = (-dy).max(0) as u32;
     let view_width = (width as i32 + dx).min(width as i32 - dx.max(0)) as u32;
@@ -53,6 +110,67 @@ pub fn offset_image(image: &RgbImage, (dx, dy): (i32, i32)) -> RgbImage {
     frame
 }
 
+/// Offset the image by a fractional amount of pixels using bilinear interpolation
+pub fn shift_subpixel<P: Pixel<Subpixel = f64>>(image: &FloatImage<P>, (dx, dy): (f64, f64)) -> FloatImage<P> {
+    let width = image.width() as i64;
+    let height = image.height() as i64;
+    let mut frame = FloatImage::<P>::new(image.width(), image.height());
+    for (x, y, pixel) in frame.enumerate_pixels_mut() {
+        let sourcex = x as f64 - dx;
+        let sourcey = y as f64 - dy;
+        let fx = sourcex - sourcex.floor();
+        let fy = sourcey - sourcey.floor();
+        let neighbours = [(0, 0, (1. - fx) * (1. - fy)), (1, 0, fx * (1. - fy)), (0, 1, (1. - fx) * fy), (1, 1, fx * fy)];
+        for (nx, ny, weight) in neighbours {
+            let sx = sourcex.floor() as i64 + nx;
+            let sy = sourcey.floor() as i64 + ny;
+            if sx < 0 || sy < 0 || sx >= width || sy >= height {
+                continue;
+            }
+            let source = image.get_pixel(sx as u32, sy as u32);
+            for (value, source) in pixel.channels_mut().iter_mut().zip(source.channels()) {
+                *value += weight * source;
+            }
+        }
+    }
+    frame
+}
+
+/// Crop rectangle as left, top, width and height within the frame
+pub fn crop_region(crop: Option<Crop>, reference: &ImageRegistration, width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let (left, top, right, bottom) = match crop {
+        None => (0, 0, width, height),
+        Some(Crop::Region(left, top, crop_width, crop_height)) => {
+            (left, top, left.saturating_add(crop_width), top.saturating_add(crop_height))
+        }
+        Some(Crop::Auto(_)) if reference.sod.left > reference.sod.right || reference.sod.top > reference.sod.bottom => {
+            println!("no object found in the reference image, not cropping");
+            (0, 0, width, height)
+        }
+        Some(Crop::Auto(margin)) => {
+            let sod = reference.sod;
+            let marginx = (sod.width() as f64 * margin).round() as u32;
+            let marginy = (sod.height() as f64 * margin).round() as u32;
+            (sod.left.saturating_sub(marginx), sod.top.saturating_sub(marginy), sod.right.saturating_add(marginx + 1), sod.bottom.saturating_add(marginy + 1))
+        }
+    };
+    let left = left.min(width - 1);
+    let top = top.min(height - 1);
+    (left, top, right.min(width) - left, bottom.min(height) - top)
+}
+
+pub fn split_channels<P: Pixel<Subpixel = f64>>(buf: &FloatImage<P>) -> Vec<Luma64FImage> {
+    (0..P::CHANNEL_COUNT as usize)
+        .map(|c| Luma64FImage::from_fn(buf.width(), buf.height(), |x, y| Luma([buf[(x, y)].channels()[c]])))
+        .collect()
+}
+pub fn merge_channels<P: Pixel<Subpixel = f64>>(channels: &[Luma64FImage]) -> FloatImage<P> {
+    FloatImage::from_fn(channels[0].width(), channels[0].height(), |x, y| {
+        let values: Vec<f64> = channels.iter().map(|channel| channel[(x, y)].0[0]).collect();
+        *P::from_slice(&values)
+    })
+}
+
 pub fn path_with_suffix<P: AsRef<Path>>(prefix: P, suffix: &str) -> PathBuf {
     let file_name = format!("{}_{}", prefix.as_ref().file_name().unwrap().to_str().unwrap(), suffix);
     prefix.as_ref().with_file_name(file_name)
@@ -95,28 +213,33 @@ pub fn edgy_sobel(image: &DynamicImage, blur_modifier: i32) -> ImageBuffer<Luma<
     buff
 }
 
-pub fn draw_object(buf: &mut Rgb64FImage, sod: SodRegistration) {
+pub fn draw_object<P: FloatPixel>(buf: &mut FloatImage<P>, sod: SodRegistration) {
     let left = (sod.left as f32, sod.middle().1 as f32);
     let right = (sod.right as f32, sod.middle().1 as f32);
     let top = (sod.middle().0 as f32, sod.top as f32);
     let bottom = (sod.middle().0 as f32, sod.bottom as f32);
-    imageproc::drawing::draw_line_segment_mut(buf, left, right, Rgb([1., 0., 0.]));
-    imageproc::drawing::draw_line_segment_mut(buf, top, bottom, Rgb([1., 0., 0.]));
+    imageproc::drawing::draw_line_segment_mut(buf, left, right, P::MARKER);
+    imageproc::drawing::draw_line_segment_mut(buf, top, bottom, P::MARKER);
 }
 
-pub fn draw_cross(buf: &mut Rgb64FImage, center: (f32, f32)) {
+pub fn draw_cross<P: FloatPixel>(buf: &mut FloatImage<P>, center: (f32, f32)) {
     let size = 20.;
     let left = (center.0 - size, center.1);
     let right = (center.0 + size, center.1);
     let top = (center.0, center.1 - size);
     let bottom = (center.0, center.1 + size);
-    imageproc::drawing::draw_line_segment_mut(buf, left, right, Rgb([1., 0., 0.]));
-    imageproc::drawing::draw_line_segment_mut(buf, top, bottom, Rgb([1., 0., 0.]));
+    imageproc::drawing::draw_line_segment_mut(buf, left, right, P::MARKER);
+    imageproc::drawing::draw_line_segment_mut(buf, top, bottom, P::MARKER);
+}
+
+pub fn draw_disk<P: FloatPixel>(buf: &mut FloatImage<P>, disk: DiskRegistration) {
+    let center = (disk.middlex.round() as i32, disk.middley.round() as i32);
+    imageproc::drawing::draw_hollow_circle_mut(buf, center, disk.radius.round() as i32, P::MARKER);
 }
 
-pub fn akaze_draw_kp(buf: &mut Rgb64FImage, keypoint: KeyPoint) {
+pub fn akaze_draw_kp<P: FloatPixel>(buf: &mut FloatImage<P>, keypoint: KeyPoint) {
     let KeyPoint { point, size, angle, .. } = keypoint;
-    let color = Rgb([1.,0.,0.]);
+    let color = P::MARKER;
     imageproc::drawing::draw_hollow_circle_mut(buf, (point.0 as i32, point.1 as i32), size as i32, color);
     let (endx, endy) = (point.0 + size * angle.cos(), point.1 + size * angle.sin());
     imageproc::drawing::draw_line_segment_mut(buf, point, (endx, endy), color);
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Condvar, mpsc, Mutex};
+use std::thread;
+use rayon::iter::{IterBridge, ParallelBridge};
+use crate::{Colorspace, CommonArgs, helpers};
+use crate::helpers::{FloatImage, FloatPixel};
+
+/// Loads frames on a separate I/O thread ahead of processing.
+///
+/// The number of frames waiting to be processed and the number of compute threads are bounded
+/// such that all frames in memory fit into `--max-memory`.
+#[derive(Debug, Copy, Clone)]
+pub struct Loader {
+    colorspace: Colorspace,
+    /// number of decoded frames waiting to be processed
+    in_flight: usize,
+    /// number of compute threads
+    workers: usize,
+}
+
+impl Loader {
+    /// `frames_per_worker` is the number of full-size frames each compute thread holds at once
+    /// (including intermediate results and accumulators), `reserved` the number of full-size frames
+    /// held independent of the number of threads.
+    pub fn new<P: FloatPixel>(common: &CommonArgs, width: u32, height: u32, frames_per_worker: usize, reserved: usize) -> Loader {
+        Loader::new_ordered::<P>(common, width, height, frames_per_worker, 0, reserved)
+    }
+
+    /// Like [`Loader::new`] for [`Loader::par_map_ordered`], whose results of `frames_per_result`
+    /// full-size frames each are held back until they can be consumed in order.
+    pub fn new_ordered<P: FloatPixel>(common: &CommonArgs, width: u32, height: u32, frames_per_worker: usize, frames_per_result: usize, reserved: usize) -> Loader {
+        let threads = rayon::current_num_threads();
+        let (workers, in_flight) = match common.max_memory {
+            None => (threads, 2 * threads),
+            Some(max_memory) => {
+                let frame_size = width as u64 * height as u64 * P::CHANNEL_COUNT as u64 * std::mem::size_of::<f64>() as u64;
+                // one frame is always being decoded by the I/O thread
+                let frames = (max_memory / frame_size.max(1)) as usize;
+                let available = frames.saturating_sub(reserved + 1);
+                // up to `in_flight + workers` results wait to be consumed, see `par_map_ordered`
+                let per_worker = frames_per_worker + frames_per_result;
+                let per_in_flight = 1 + frames_per_result;
+                if available < per_worker + per_in_flight {
+                    println!("--max-memory {max_memory} is too small for frames of {frame_size} bytes, continuing with a single thread");
+                }
+                let workers = (available.saturating_sub(per_in_flight) / per_worker).clamp(1, threads);
+                let in_flight = (available.saturating_sub(workers * per_worker) / per_in_flight).clamp(1, 2 * workers);
+                (workers, in_flight)
+            }
+        };
+        let in_flight = common.prefetch.unwrap_or(in_flight).max(1);
+        println!("Loading up to {in_flight} frames ahead, processing with {workers} threads");
+        Loader { colorspace: common.colorspace, in_flight, workers }
+    }
+
+    /// Load the images of the given items in order on the I/O thread and pass them to `f`
+    pub fn load<P, T, R>(&self, items: Vec<T>, path: impl Fn(&T) -> &Path + Send, f: impl FnOnce(mpsc::IntoIter<(T, FloatImage<P>)>) -> R) -> R
+    where
+        P: FloatPixel,
+        T: Send,
+    {
+        self.load_limited(items, path, None, f)
+    }
+
+    /// Like [`Loader::load`], but the I/O thread takes one of the `permits` before loading each frame
+    fn load_limited<P, T, R>(&self, items: Vec<T>, path: impl Fn(&T) -> &Path + Send, permits: Option<&Permits>, f: impl FnOnce(mpsc::IntoIter<(T, FloatImage<P>)>) -> R) -> R
+    where
+        P: FloatPixel,
+        T: Send,
+    {
+        let colorspace = self.colorspace;
+        let (tx, rx) = mpsc::sync_channel(self.in_flight);
+        thread::scope(|s| {
+            s.spawn(move || {
+                for item in items {
+                    if let Some(permits) = permits {
+                        permits.acquire();
+                    }
+                    let image = helpers::load_image(path(&item), colorspace);
+                    // the receiver was dropped, i.e. processing finished early
+                    if tx.send((item, image)).is_err() {
+                        break;
+                    }
+                }
+            });
+            f(rx.into_iter())
+        })
+    }
+
+    /// Like [`Loader::load`], but processes the frames in parallel (in no particular order)
+    /// on a thread pool of at most `workers` threads
+    pub fn par_load<P, T, R>(&self, items: Vec<T>, path: impl Fn(&T) -> &Path + Send, f: impl FnOnce(IterBridge<mpsc::IntoIter<(T, FloatImage<P>)>>) -> R + Send) -> R
+    where
+        P: FloatPixel,
+        T: Send,
+        R: Send,
+    {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.workers)
+            .build()
+            .unwrap();
+        // since rayon 1.6.1 `par_bridge` takes one frame at a time when a thread is idle, before it
+        // buffered frames in its own queues beyond `in_flight`
+        self.load(items, path, |frames| pool.install(|| f(frames.par_bridge())))
+    }
+
+    /// Like [`Loader::par_load`], but passes the results of `map` to `consume` in the order of the items.
+    ///
+    /// `consume` runs on the calling thread. At most as many frames as can be loaded ahead and processed
+    /// at once are between loading and consuming, which the I/O thread waits for before loading more.
+    ///
+    /// `map` runs on `workers` separate threads instead of a rayon pool: a rayon thread waiting within
+    /// nested parallelism of `map` could steal the job taking the next frame, which may only be loaded
+    /// once the result the thread is working on has been consumed.
+    pub fn par_map_ordered<P, T, U>(&self, items: Vec<T>, path: impl Fn(&T) -> &Path + Send, map: impl Fn(T, FloatImage<P>) -> U + Sync, consume: impl FnMut(U))
+    where
+        P: FloatPixel,
+        T: Send,
+        U: Send,
+    {
+        let permits = Permits::new(self.in_flight + self.workers);
+        let (tx, rx) = mpsc::channel::<(usize, U)>();
+        let items: Vec<_> = items.into_iter().enumerate().collect();
+        self.load_limited(items, move |(_, item)| path(item), Some(&permits), |frames| {
+            // dropped with the last worker, such that the I/O thread stops if all of them panicked
+            let frames = Arc::new(Mutex::new(frames));
+            thread::scope(|s| {
+                for _ in 0..self.workers {
+                    let (frames, tx, map) = (Arc::clone(&frames), tx.clone(), &map);
+                    s.spawn(move || loop {
+                        let Some(((index, item), image)) = frames.lock().unwrap().next() else {
+                            break;
+                        };
+                        tx.send((index, map(item, image))).unwrap();
+                    });
+                }
+                drop((frames, tx));
+                // also unblock the I/O thread if `consume` panics
+                let _close = ClosePermits(&permits);
+                let mut consume = consume;
+                let mut pending = BTreeMap::new();
+                let mut expected = 0;
+                for (index, result) in rx {
+                    pending.insert(index, result);
+                    while let Some(result) = pending.remove(&expected) {
+                        consume(result);
+                        expected += 1;
+                        permits.release();
+                    }
+                }
+            });
+        });
+    }
+}
+
+/// Counting semaphore limiting the frames between loading and consuming in [`Loader::par_map_ordered`]
+struct Permits {
+    /// number of available permits, `None` once closed
+    available: Mutex<Option<usize>>,
+    released: Condvar,
+}
+
+impl Permits {
+    fn new(permits: usize) -> Permits {
+        Permits { available: Mutex::new(Some(permits)), released: Condvar::new() }
+    }
+
+    /// Wait for a permit, returning immediately once closed
+    fn acquire(&self) {
+        let mut available = self.available.lock().unwrap();
+        while *available == Some(0) {
+            available = self.released.wait(available).unwrap();
+        }
+        if let Some(available) = available.as_mut() {
+            *available -= 1;
+        }
+    }
+
+    fn release(&self) {
+        if let Some(available) = self.available.lock().unwrap().as_mut() {
+            *available += 1;
+        }
+        self.released.notify_one();
+    }
+
+    fn close(&self) {
+        *self.available.lock().unwrap() = None;
+        self.released.notify_one();
+    }
+}
+
+/// Closes the permits when dropped
+struct ClosePermits<'a>(&'a Permits);
+
+impl Drop for ClosePermits<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
@@ -1,15 +1,31 @@
+use std::fmt::Display;
 use std::path::PathBuf;
+use std::str::FromStr;
 use clap::{builder::ValueParser, Parser, ValueEnum, Args, Subcommand};
 
 mod helpers;
 mod processing;
+mod calibration;
 mod colorspace;
+mod combine;
+mod deconvolution;
+mod denoise;
+mod derotation;
+mod encoding;
+mod export;
+mod font;
+mod loader;
 mod process;
+mod quality;
 mod compare;
 mod register;
 mod video;
 mod stack;
+mod reject;
 mod rejection;
+mod smoothing;
+mod source;
+mod wavelets;
 
 fn main() {
     let args: Cli = Cli::parse();
@@ -21,6 +37,9 @@ fn main() {
         Command::Compare(cmp) => compare::compare(args.common, cmp),
         Command::Video(video) => video::video(args.common, video),
         Command::Stack(stack) => stack::stack(args.common, stack),
+        Command::Combine(combine) => combine::combine(args.common, combine),
+        Command::Reject(reject) => reject::reject(args.common, reject),
+        Command::Export(export) => export::export(args.common, export),
     }
 }
 
@@ -41,6 +60,15 @@ pub struct CommonArgs {
     num_files: usize,
     #[arg(global = true, short = 's', default_value_t = 0)]
     skip_files: usize,
+    /// process the images as monochrome, using a third of the memory of colour images
+    #[arg(global = true, long)]
+    mono: bool,
+    /// upper bound for the memory used by frames, e.g. `8G`; limits how many frames are loaded and processed at once
+    #[arg(global = true, long, value_parser=ValueParser::new(parse_memory))]
+    max_memory: Option<u64>,
+    /// number of frames loaded ahead of processing [default: twice the number of threads, bounded by --max-memory]
+    #[arg(global = true, long)]
+    prefetch: Option<usize>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -55,6 +83,12 @@ pub enum Command {
     Video(Video),
     /// Stack registered images
     Stack(Stack),
+    /// Register stacked channel images onto each other and combine them into a colour image
+    Combine(Combine),
+    /// Apply the rejection to a registration, writing a report and the filtered registration
+    Reject(Reject),
+    /// Write the aligned registered frames as 16 bit PNG, TIFF or FITS sequence or as SER file
+    Export(Export),
 }
 
 #[derive(Debug, Args)]
@@ -92,6 +126,7 @@ pub struct Compare {
 
 #[derive(Debug, Args)]
 pub struct Register {
+    /// image files, directories of image files, or MP4 (H.264), AVI (uncompressed or MJPEG) and SER videos
     #[arg(short = 'i', long)]
     imagepaths: Vec<PathBuf>,
     #[arg(short = 'r', long, default_value_t = 0)]
@@ -114,6 +149,19 @@ pub struct Register {
     single_object_detection: f64,
     #[arg(long, long = "aba", default_value_t = 0.2)]
     average_brightness_alignment: f64,
+    /// fit a circle to the limb of a planetary disk, taking sobel edges above this fraction of the
+    /// strongest edge as limb points, e.g. 0.5
+    #[arg(long)]
+    disk: Option<f64>,
+    /// number of frames of the window the drift trajectory is smoothed over
+    #[arg(long, default_value_t = 9)]
+    smooth_window: usize,
+    /// frames deviating more than this many sigmas from the smoothed trajectory snap to it
+    #[arg(long, default_value_t = 3.)]
+    snap: f32,
+    /// replace rejected akaze registrations by the smoothed akaze trajectory
+    #[arg(long)]
+    interpolate_akaze: bool,
 }
 
 #[derive(Debug, Args)]
@@ -122,11 +170,54 @@ pub struct Video {
     registration_input: PathBuf,
     #[arg(short = 'r', long, value_parser=ValueParser::new(parse_rejection), value_delimiter=',')]
     rejection: Vec<Rejection>,
+    /// how the verdicts of the rejection rules are combined
+    #[arg(long, value_enum, default_value = "any")]
+    rejection_policy: RejectionPolicy,
     #[arg(
         short = 'p', long, value_parser=ValueParser::new(parse_postprocessing), value_delimiter=',',
         default_value = "maxscale",
     )]
     processing: Vec<Processing>,
+    /// registration results to create aligned videos for
+    #[arg(short = 'm', long, value_enum, value_delimiter = ',', default_value = "akaze,sod,aba")]
+    methods: Vec<RegistrationMethod>,
+    /// don't create the video of the unaligned frames
+    #[arg(long)]
+    no_orig: bool,
+    /// frame rate of the videos
+    #[arg(long, default_value_t = 25)]
+    fps: u32,
+    /// target bitrate in kbit/s [default: encoder default]
+    #[arg(long)]
+    bitrate: Option<u32>,
+    /// scale the frames by this factor after cropping
+    #[arg(long, default_value_t = 1.)]
+    scale: f32,
+    /// crop the frames to `x/y/w/h` or `auto/margin`, cropping around the object of the reference image
+    /// with a margin relative to its size
+    #[arg(long, value_parser=ValueParser::new(parse_crop))]
+    crop: Option<Crop>,
+    /// compose the videos into a single labeled grid video; with a rejection, the aligned videos are
+    /// shown once with all frames and once holding the last accepted frame over rejected frames
+    #[arg(long)]
+    grid: bool,
+    /// annotate the frames with their index and file, the offsets of each method, the rejection verdict
+    /// and the frame statistics, and mark the detected object, its brightness centre and the fitted disk
+    #[arg(long)]
+    overlay: bool,
+    /// write 16 bit samples to `.y4m` and `.png` videos instead of 8 bit, MP4 and GIF are always 8 bit
+    #[arg(long)]
+    high_bit_depth: bool,
+    /// derotate the planetary disk of every frame onto the rotation of the planet at `--epoch`
+    #[arg(long, value_enum)]
+    derotate: Option<Planet>,
+    /// position angle of the north pole of the planet in the frames in degrees, counter-clockwise from up
+    #[arg(long, default_value_t = 0.)]
+    pole_angle: f64,
+    /// time to derotate onto in seconds since the Unix epoch [default: middle of the session]
+    #[arg(long)]
+    epoch: Option<f64>,
+    /// prefix of the output files, its extension selects the format: `.mp4`, `.gif`, `.png` (APNG) or `.y4m`
     #[arg(short = 'o', long, default_value = "video_aligned")]
     outfile_prefix: PathBuf,
 }
@@ -140,6 +231,9 @@ pub struct Stack {
         default_value = "regressionaba,widthheight",
     )]
     rejection: Vec<Rejection>,
+    /// how the verdicts of the rejection rules are combined
+    #[arg(long, value_enum, default_value = "any")]
+    rejection_policy: RejectionPolicy,
     #[arg(
         long = "pre", value_parser=ValueParser::new(parse_postprocessing), value_delimiter=',',
         default_value = "bgone=0.025",
@@ -150,10 +244,218 @@ pub struct Stack {
         default_value = "maxscale",
     )]
     postprocessing: Vec<Processing>,
+    /// registration results to stack, each one creating its own output
+    #[arg(short = 'm', long, value_enum, value_delimiter = ',', default_value = "akaze,sod,aba")]
+    methods: Vec<RegistrationMethod>,
+    /// derotate the planetary disk of every frame onto the rotation of the planet at `--epoch`
+    #[arg(long, value_enum)]
+    derotate: Option<Planet>,
+    /// position angle of the north pole of the planet in the frames in degrees, counter-clockwise from up
+    #[arg(long, default_value_t = 0.)]
+    pole_angle: f64,
+    /// time to derotate onto in seconds since the Unix epoch [default: middle of the session]
+    #[arg(long)]
+    epoch: Option<f64>,
     #[arg(short = 'o', long, default_value = "stacked")]
     outfile_prefix: PathBuf,
 }
 
+#[derive(Debug, Args)]
+pub struct Reject {
+    #[arg(short = 'i', long, default_value = "registration_data.json")]
+    registration_input: PathBuf,
+    #[arg(
+        short = 'r', long, value_parser=ValueParser::new(parse_rejection), value_delimiter=',',
+        default_value = "regressionaba,widthheight",
+    )]
+    rejection: Vec<Rejection>,
+    /// how the verdicts of the rejection rules are combined
+    #[arg(long, value_enum, default_value = "any")]
+    rejection_policy: RejectionPolicy,
+    #[arg(short = 'o', long, default_value = "registration_filtered.json")]
+    outfile: PathBuf,
+    /// creates `<report>.json` with the verdict of every rule for every frame and `<report>.png` plotting them
+    #[arg(long, default_value = "rejection_report")]
+    report: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct Export {
+    #[arg(short = 'i', long, default_value = "registration_data.json")]
+    registration_input: PathBuf,
+    #[arg(
+        short = 'r', long, value_parser=ValueParser::new(parse_rejection), value_delimiter=',',
+        default_value = "regressionaba,widthheight",
+    )]
+    rejection: Vec<Rejection>,
+    /// how the verdicts of the rejection rules are combined
+    #[arg(long, value_enum, default_value = "any")]
+    rejection_policy: RejectionPolicy,
+    #[arg(short = 'p', long, value_parser=ValueParser::new(parse_postprocessing), value_delimiter=',')]
+    processing: Vec<Processing>,
+    /// registration result to align the frames with
+    #[arg(short = 'm', long, value_enum, default_value = "best")]
+    method: RegistrationMethod,
+    /// shift the frames by the unrounded offsets with bilinear interpolation
+    #[arg(long)]
+    subpixel: bool,
+    /// crop the frames to `x/y/w/h` or `auto/margin`, cropping around the object of the reference image
+    /// with a margin relative to its size
+    #[arg(long, value_parser=ValueParser::new(parse_crop))]
+    crop: Option<Crop>,
+    /// `.ser` writes a single file, `.png`, `.tif` and `.fits` write one file per frame suffixed by its index
+    #[arg(short = 'o', long, default_value = "aligned.png")]
+    outfile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct Combine {
+    /// channel image as `channel=path`, the first one is the registration reference
+    #[arg(short = 'i', long, value_parser=ValueParser::new(parse_channel_image), required = true)]
+    images: Vec<(Channel, PathBuf)>,
+    #[arg(short = 'm', long, value_enum, default_value = "rgb")]
+    mapping: Mapping,
+    /// weights of the red output channel for the custom mapping, e.g. `ha=0.8,sii=0.2`
+    #[arg(long = "red", value_parser=ValueParser::new(parse_channel_weight), value_delimiter=',')]
+    mix_red: Vec<(Channel, f64)>,
+    #[arg(long = "green", value_parser=ValueParser::new(parse_channel_weight), value_delimiter=',')]
+    mix_green: Vec<(Channel, f64)>,
+    #[arg(long = "blue", value_parser=ValueParser::new(parse_channel_weight), value_delimiter=',')]
+    mix_blue: Vec<(Channel, f64)>,
+    #[arg(long, value_enum, default_value = "aba")]
+    registration: RegistrationMethod,
+    #[arg(
+        long = "pa", long, value_parser=ValueParser::new(parse_postprocessing), value_delimiter=',',
+        default_value = "maxscale,blur=20,sobel=0,maxscale",
+    )]
+    preprocessing_akaze: Vec<Processing>,
+    #[arg(
+        long = "pr", long, value_parser=ValueParser::new(parse_postprocessing), value_delimiter=',',
+        default_value = "maxscale,blur=20,maxscale",
+    )]
+    preprocessing_rest: Vec<Processing>,
+    #[arg(long, default_value_t = 0.001)]
+    akaze: f64,
+    #[arg(long, long = "sod", default_value_t = 0.2)]
+    single_object_detection: f64,
+    #[arg(long, long = "aba", default_value_t = 0.2)]
+    average_brightness_alignment: f64,
+    /// fit a circle to the limb of a planetary disk, taking sobel edges above this fraction of the
+    /// strongest edge as limb points, e.g. 0.5
+    #[arg(long)]
+    disk: Option<f64>,
+    #[arg(
+        short = 'p', long, value_parser=ValueParser::new(parse_postprocessing), value_delimiter=',',
+        default_value = "maxscale",
+    )]
+    processing: Vec<Processing>,
+    #[arg(short = 'o', long, default_value = "combined.png")]
+    outfile: PathBuf,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Channel {
+    L,
+    R,
+    G,
+    B,
+    Ha,
+    Oiii,
+    Sii,
+}
+fn parse_memory(p: &str) -> Result<u64, String> {
+    let (number, unit) = match p.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => p.split_at(index),
+        None => (p, ""),
+    };
+    let factor: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("unknown unit `{unit}`, allowed: K, M, G, T")),
+    };
+    let number: f64 = number.trim().parse().map_err(|e| format!("{e}"))?;
+    Ok((number * factor as f64) as u64)
+}
+fn parse_channel_image(p: &str) -> Result<(Channel, PathBuf), String> {
+    let (channel, path) = p.split_once('=').ok_or_else(|| format!("expected `channel=path`, got `{p}`"))?;
+    Ok((Channel::from_str(channel, true)?, PathBuf::from(path)))
+}
+fn parse_channel_weight(p: &str) -> Result<(Channel, f64), String> {
+    let (channel, weight) = p.split_once('=').ok_or_else(|| format!("expected `channel=weight`, got `{p}`"))?;
+    Ok((Channel::from_str(channel, true)?, weight.parse().map_err(|e| format!("{e}"))?))
+}
+
+/// Mapping of the channels into RGB. If a luminance channel is given, it replaces the
+/// luminance of the result (LRGB).
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum Mapping {
+    Rgb,
+    /// Ha as red, OIII as green and blue
+    Hoo,
+    /// Hubble palette: SII as red, Ha as green, OIII as blue
+    Sho,
+    /// weights given by `--red`, `--green` and `--blue`
+    Custom,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum RegistrationMethod {
+    /// AKAZE, including the offsets interpolated for rejected frames
+    Akaze,
+    Sod,
+    Aba,
+    /// centre of the circle fitted to the limb of a planetary disk
+    Disk,
+    /// AKAZE where measured, falling back to ABA where it's missing, rejected or only interpolated
+    Best,
+    /// outlier-aware weighted mean of all methods, with the per-method noise estimated across all frames
+    Fused,
+    /// fused offsets, snapping noisy frames to the smoothed drift trajectory
+    Smoothed,
+}
+impl RegistrationMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            RegistrationMethod::Akaze => "akaze",
+            RegistrationMethod::Sod => "sod",
+            RegistrationMethod::Aba => "aba",
+            RegistrationMethod::Disk => "disk",
+            RegistrationMethod::Best => "best",
+            RegistrationMethod::Fused => "fused",
+            RegistrationMethod::Smoothed => "smoothed",
+        }
+    }
+}
+
+/// Planet whose rotation is compensated by derotation
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum Planet {
+    Mars,
+    Jupiter,
+    Saturn,
+}
+impl Planet {
+    /// Sidereal rotation period in seconds, System II for Jupiter and System III for Saturn
+    pub fn rotation_period(self) -> f64 {
+        match self {
+            Planet::Mars => 88642.66,
+            Planet::Jupiter => 35740.6,
+            Planet::Saturn => 38362.4,
+        }
+    }
+    /// Flattening of the disk, 1 - polar radius / equatorial radius
+    pub fn flattening(self) -> f64 {
+        match self {
+            Planet::Mars => 0.00589,
+            Planet::Jupiter => 0.06487,
+            Planet::Saturn => 0.09796,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, ValueEnum)]
 pub enum Colorspace {
     Srgb,
@@ -162,7 +464,7 @@ pub enum Colorspace {
     Sqrt,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub enum Processing {
     Average,
     Maxscale,
@@ -170,12 +472,33 @@ pub enum Processing {
     Sqrt,
     Asinh,
     Sharpen,
+    /// à trous wavelet sharpening with `(gain, denoise)` per layer, finest layer first
+    Wavelets(Vec<(f64, f64)>),
+    /// unsharp mask with radius, amount and threshold, optionally on luminance only and
+    /// restricted to the object detected by the given mask threshold
+    UnsharpMask(f32, f64, f64, bool, Option<f64>),
+    /// Richardson-Lucy deconvolution with the given iterations, total variation regularization and PSF
+    RichardsonLucy(u32, f64, Psf),
+    /// Wiener deconvolution with the given noise-to-signal ratio and PSF
+    Wiener(f64, Psf),
     /// sobel edgeg detection with passed blur, 1 by default
     Sobel(i32),
     /// gaussian blur with passed sigma, 1.0 by default
     Blur(f32),
     /// Median of a single image of pixels in the given radius
     Median(u32),
+    /// bilateral filter with spatial sigma and range sigma for luminance and chrominance
+    Bilateral(f32, f64, f64),
+    /// non-local means with strength for luminance and chrominance, search radius and patch radius
+    NonLocalMeans(f64, f64, u32, u32),
+    /// wavelet denoise with threshold for luminance and chrominance in multiples of the noise and number of layers
+    WaveletDenoise(f64, f64, usize),
+    /// shift the channels such that the background below the given threshold is neutral (0.1)
+    NeutralizeBackground(f64),
+    /// scale the channels such that the reference is white
+    WhiteBalance(WhiteReference),
+    /// register red and blue onto green using average brightness alignment with the given threshold (0.2)
+    AlignChannels(f64),
     /// bg extraction using the given threshold to make pixels black (0.2)
     BGone(f64),
     /// convert the image to a black-white image using the given threshold (0.5)
@@ -210,10 +533,52 @@ fn parse_postprocessing(p: &str) -> Result<Processing, String> {
         "sqrt" => no_value(Processing::Sqrt),
         "asinh" => no_value(Processing::Asinh),
         "sharpen" => no_value(Processing::Sharpen),
+        "wavelets" => Ok(Processing::Wavelets(parse_wavelet_layers(value.unwrap_or("1.8:1.4:1.2:1:1:1"))?)),
         "akaze" => Ok(Processing::Akaze(value!(value, 0.0008))),
+        "usm" => {
+            let mut parts = value.unwrap_or("2:0.8:0").split(':');
+            let radius = value!(parts.next(), 2.0);
+            let amount = value!(parts.next(), 0.8);
+            let threshold = value!(parts.next(), 0.0);
+            let luminance = match parts.next() {
+                None | Some("rgb") => false,
+                Some("lum") => true,
+                Some(mode) => return Err(format!("unknown usm mode `{mode}`, allowed values are `rgb` and `lum`")),
+            };
+            let mask = parts.next().map(|s| s.parse()).transpose().map_err(|e| format!("{e}"))?;
+            Ok(Processing::UnsharpMask(radius, amount, threshold, luminance, mask))
+        }
+        "rl" => {
+            let mut parts = value.unwrap_or("20:0.002:gauss/1.5").splitn(3, ':');
+            let iterations = value!(parts.next(), 20);
+            let regularization = value!(parts.next(), 0.002);
+            let psf = parse_psf(parts.next().unwrap_or("gauss/1.5"))?;
+            Ok(Processing::RichardsonLucy(iterations, regularization, psf))
+        }
+        "wiener" => {
+            let mut parts = value.unwrap_or("0.01:gauss/1.5").splitn(2, ':');
+            let noise = value!(parts.next(), 0.01);
+            let psf = parse_psf(parts.next().unwrap_or("gauss/1.5"))?;
+            Ok(Processing::Wiener(noise, psf))
+        }
         "sobel" => Ok(Processing::Sobel(value!(value, 0))),
         "blur" => Ok(Processing::Blur(value!(value, 1.0))),
         "median" => Ok(Processing::Median(value!(value, 2))),
+        "bilateral" => {
+            let mut parts = value.unwrap_or("2:0.05:0.1").split(':');
+            Ok(Processing::Bilateral(value!(parts.next(), 2.0), value!(parts.next(), 0.05), value!(parts.next(), 0.1)))
+        }
+        "nlm" => {
+            let mut parts = value.unwrap_or("0.05:0.1:5:1").split(':');
+            Ok(Processing::NonLocalMeans(value!(parts.next(), 0.05), value!(parts.next(), 0.1), value!(parts.next(), 5), value!(parts.next(), 1)))
+        }
+        "wdenoise" => {
+            let mut parts = value.unwrap_or("2:3:4").split(':');
+            Ok(Processing::WaveletDenoise(value!(parts.next(), 2.0), value!(parts.next(), 3.0), value!(parts.next(), 4)))
+        }
+        "bgneutral" => Ok(Processing::NeutralizeBackground(value!(value, 0.1))),
+        "wb" => Ok(Processing::WhiteBalance(parse_white_reference(value.unwrap_or("stars/0.5"))?)),
+        "align" => Ok(Processing::AlignChannels(value!(value, 0.2))),
         "bgone" => Ok(Processing::BGone(value!(value, 0.2))),
         "bw" => Ok(Processing::BlackWhite(value!(value, 0.2))),
         "sod" => Ok(Processing::SingleObjectDetection(value!(value, 0.2))),
@@ -221,11 +586,122 @@ fn parse_postprocessing(p: &str) -> Result<Processing, String> {
         _ => Err(format!(
             "unknown processing `{typ}`, allowed values are `average`, `maxscale`,\
             `sqrt`, `asinh`, `akaze=0.0008`, `sobel=0`, `blur=1.0`, `bgone=0.2`,\
-            `bw=0.2`, `sod=0.2`, `aba=0.2`."
+            `bw=0.2`, `sod=0.2`, `aba=0.2`, `wavelets=1.8:1.4:1.2:1:1:1`,\
+            `usm=2:0.8:0:rgb:0.1`, `rl=20:0.002:gauss/1.5`, `wiener=0.01:gauss/1.5`,\
+            `bilateral=2:0.05:0.1`, `nlm=0.05:0.1:5:1`, `wdenoise=2:3:4`, `bgneutral=0.1`,\
+            `wb=stars/0.5`, `wb=region/x/y/w/h`, `align=0.2`."
+        ))
+    }
+}
+/// parse colon-separated layers, each either `gain` or `gain/denoise`
+fn parse_wavelet_layers(value: &str) -> Result<Vec<(f64, f64)>, String> {
+    value.split(':').map(|layer| {
+        let mut parts = layer.split('/');
+        let gain = parts.next().unwrap().parse().map_err(|e| format!("{e}"))?;
+        let denoise = parts.next().map(|s| s.parse()).unwrap_or(Ok(0.)).map_err(|e| format!("{e}"))?;
+        Ok((gain, denoise))
+    }).collect()
+}
+
+/// point spread function used for deconvolution
+#[derive(Debug, Copy, Clone)]
+pub enum Psf {
+    /// gaussian with the given sigma
+    Gaussian(f64),
+    /// moffat with the given fwhm and beta
+    Moffat(f64, f64),
+    /// star cut out of the image at the given x, y and radius
+    Star(u32, u32, u32),
+    /// gaussian estimated from the limb of the object detected with the given threshold (0.2)
+    Limb(f64),
+}
+fn parse_psf(p: &str) -> Result<Psf, String> {
+    let (typ, values) = p.split_once('/').unwrap_or((p, ""));
+    match typ {
+        "gauss" => {
+            let [sigma] = parse_fields(values)?;
+            Ok(Psf::Gaussian(sigma.unwrap_or(1.5)))
+        }
+        "moffat" => {
+            let [fwhm, beta] = parse_fields(values)?;
+            Ok(Psf::Moffat(fwhm.unwrap_or(3.0), beta.unwrap_or(2.5)))
+        }
+        "star" => match parse_fields(values)? {
+            [_, _, Some(0)] => Err("the radius of the star psf must be at least 1".to_string()),
+            [Some(x), Some(y), radius] => Ok(Psf::Star(x, y, radius.unwrap_or(10))),
+            _ => Err("star psf needs `star/x/y[/radius]`".to_string()),
+        },
+        "limb" => {
+            let [threshold] = parse_fields(values)?;
+            Ok(Psf::Limb(threshold.unwrap_or(0.2)))
+        }
+        _ => Err(format!(
+            "unknown psf `{typ}`, allowed values are `gauss/1.5`, `moffat/3.0/2.5`,\
+            `star/x/y/10`, `limb/0.2`."
+        ))
+    }
+}
+
+/// reference used for white balance
+#[derive(Debug, Copy, Clone)]
+pub enum WhiteReference {
+    /// rectangle given by left, top, width and height
+    Region(u32, u32, u32, u32),
+    /// average of all stars brighter than the given threshold
+    Stars(f64),
+}
+fn parse_white_reference(p: &str) -> Result<WhiteReference, String> {
+    let (typ, values) = p.split_once('/').unwrap_or((p, ""));
+    match typ {
+        "region" => {
+            let [x, y, width, height] = parse_fields(values)?;
+            Ok(WhiteReference::Region(x.unwrap_or(0), y.unwrap_or(0), width.unwrap_or(u32::MAX), height.unwrap_or(u32::MAX)))
+        }
+        "stars" => {
+            let [threshold] = parse_fields(values)?;
+            Ok(WhiteReference::Stars(threshold.unwrap_or(0.5)))
+        }
+        _ => Err(format!(
+            "unknown white balance reference `{typ}`, allowed values are `region/x/y/w/h`, `stars/0.5`."
         ))
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+pub enum Crop {
+    /// rectangle given by left, top, width and height
+    Region(u32, u32, u32, u32),
+    /// single object detection box of the reference image extended by the given fraction of its size
+    Auto(f64),
+}
+fn parse_crop(p: &str) -> Result<Crop, String> {
+    let (typ, values) = p.split_once('/').unwrap_or((p, ""));
+    if typ == "auto" {
+        let [margin] = parse_fields(values)?;
+        return Ok(Crop::Auto(margin.unwrap_or(0.1)));
+    }
+    match parse_fields(p) {
+        Ok([Some(x), y, width, height]) => Ok(Crop::Region(x, y.unwrap_or(0), width.unwrap_or(u32::MAX), height.unwrap_or(u32::MAX))),
+        _ => Err(format!("expected `x/y/w/h` or `auto/0.1`, got `{p}`")),
+    }
+}
+
+/// Parse up to `N` values separated by `/`, e.g. the `3.0/2.5` of `moffat/3.0/2.5`, missing values are `None`
+fn parse_fields<T: FromStr, const N: usize>(values: &str) -> Result<[Option<T>; N], String>
+where
+    T::Err: Display,
+{
+    let mut fields = std::array::from_fn(|_| None);
+    if values.is_empty() {
+        return Ok(fields);
+    }
+    for (i, value) in values.split('/').enumerate() {
+        let field = fields.get_mut(i).ok_or_else(|| format!("expected at most {N} values separated by `/`, got `{values}`"))?;
+        *field = Some(value.parse().map_err(|e| format!("{e}"))?);
+    }
+    Ok(fields)
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Rejection {
     AverageSod(f32),
@@ -233,7 +709,44 @@ pub enum Rejection {
     RegressionAkaze(f32),
     RegressionSod(f32),
     RegressionAba(f32),
+    /// change of the disk radius, or of the single object detection box for frames without disk fit
     WidthHeight(f32),
+    /// deviation of the mean brightness from the median frame, e.g. due to clouds
+    Brightness(Threshold),
+    /// high background level
+    Background(Threshold),
+    /// low number of stars
+    Stars(Threshold),
+    /// large FWHM of the stars
+    Fwhm(Threshold),
+    /// low number of akaze inliers
+    AkazeInliers(Threshold),
+    /// low variance of the laplacian
+    Sharpness(Threshold),
+}
+/// Rejection rules a frame must fail to be rejected
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum RejectionPolicy {
+    Any,
+    All,
+    Majority,
+}
+/// Threshold of statistics-based rejections
+#[derive(Debug, Copy, Clone)]
+pub enum Threshold {
+    /// reject frames deviating more than this many (robust) standard deviations from the median
+    Sigma(f32),
+    /// reject the given percentage of the worst frames
+    Percentile(f32),
+}
+fn parse_threshold(value: Option<&str>) -> Result<Threshold, String> {
+    match value {
+        None => Ok(Threshold::Sigma(3.)),
+        Some(value) => match value.strip_suffix('%') {
+            Some(percentile) => Ok(Threshold::Percentile(percentile.parse().map_err(|e| format!("{e}"))?)),
+            None => Ok(Threshold::Sigma(value.parse().map_err(|e| format!("{e}"))?)),
+        }
+    }
 }
 fn parse_rejection(p: &str) -> Result<Rejection, String> {
     let mut parts = p.split("=");
@@ -251,9 +764,17 @@ fn parse_rejection(p: &str) -> Result<Rejection, String> {
         "regressionsod" => Ok(Rejection::RegressionSod(value!(value, 0.001))),
         "regressionaba" => Ok(Rejection::RegressionAba(value!(value, 0.001))),
         "widthheight" => Ok(Rejection::WidthHeight(value!(value, 0.02))),
+        "brightness" => Ok(Rejection::Brightness(parse_threshold(value)?)),
+        "background" => Ok(Rejection::Background(parse_threshold(value)?)),
+        "stars" => Ok(Rejection::Stars(parse_threshold(value)?)),
+        "fwhm" => Ok(Rejection::Fwhm(parse_threshold(value)?)),
+        "akazeinliers" => Ok(Rejection::AkazeInliers(parse_threshold(value)?)),
+        "sharpness" => Ok(Rejection::Sharpness(parse_threshold(value)?)),
         _ => Err(format!(
             "unknown rejection `{typ}`, allowed values are `averagesod=0.01`, `averageaba=0.01`,\
-            `regressionakaze=0.001`, `regressionsod=0.001`, `regressionaba=0.001`."
+            `regressionakaze=0.001`, `regressionsod=0.001`, `regressionaba=0.001`, `widthheight=0.02`,\
+            `brightness=3`, `background=3`, `stars=3`, `fwhm=3`, `akazeinliers=3`, `sharpness=3`\
+            (in sigma or as percentage of the worst frames, e.g. `sharpness=10%`)."
         ))
     }
 }
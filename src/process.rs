@@ -1,9 +1,19 @@
+use image::{Luma, Rgb};
 use crate::{CommonArgs, helpers, Process, processing};
+use crate::helpers::FloatPixel;
 
 pub fn process(common: CommonArgs, process: Process) {
-    let CommonArgs { colorspace, num_files, skip_files: _ } = common;
+    if common.mono {
+        process_generic::<Luma<f64>>(common, process)
+    } else {
+        process_generic::<Rgb<f64>>(common, process)
+    }
+}
+
+fn process_generic<P: FloatPixel>(common: CommonArgs, process: Process) {
+    let CommonArgs { colorspace, num_files, skip_files: _, mono: _, max_memory: _, prefetch: _ } = common;
     let Process { image, processing, outfile } = process;
-    let mut img = helpers::load_image(image, colorspace);
+    let mut img = helpers::load_image::<P, _>(image, colorspace);
     processing::process(&mut img, num_files, &processing);
     helpers::save_image(img, outfile, colorspace);
 }
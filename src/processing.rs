@@ -1,8 +1,10 @@
-use image::{DynamicImage, imageops, Rgb, Rgb64FImage};
+use image::{DynamicImage, imageops};
 use ordered_float::NotNan;
-use crate::{helpers, Processing, register};
+use rayon::prelude::*;
+use crate::{calibration, deconvolution, denoise, helpers, Processing, register, wavelets};
+use crate::helpers::{FloatImage, FloatPixel};
 
-pub fn process(buf: &mut Rgb64FImage, num_files: usize, processing: &[Processing]) {
+pub fn process<P: FloatPixel>(buf: &mut FloatImage<P>, num_files: usize, processing: &[Processing]) {
     for postprocess in processing {
         match postprocess {
             Processing::Average => average(buf, num_files),
@@ -11,9 +13,19 @@ pub fn process(buf: &mut Rgb64FImage, num_files: usize, processing: &[Processing
             Processing::Sqrt => sqrt(buf),
             Processing::Asinh => asinh(buf),
             Processing::Sharpen => sharpen(buf),
+            &Processing::UnsharpMask(radius, amount, threshold, luminance, mask) => unsharp_mask(buf, radius, amount, threshold, luminance, mask),
+            Processing::Wavelets(layers) => wavelets::sharpen(buf, layers),
+            &Processing::RichardsonLucy(iterations, regularization, psf) => deconvolution::richardson_lucy(buf, iterations, regularization, psf),
+            &Processing::Wiener(noise, psf) => deconvolution::wiener(buf, noise, psf),
             &Processing::Sobel(blur) => sobel(buf, blur),
             &Processing::Blur(sigma) => gaussian_blur(buf, sigma),
             &Processing::Median(radius) => median(buf, radius),
+            &Processing::Bilateral(sigma_spatial, sigma_luma, sigma_chroma) => denoise::bilateral(buf, sigma_spatial, sigma_luma, sigma_chroma),
+            &Processing::NonLocalMeans(h_luma, h_chroma, search_radius, patch_radius) => denoise::non_local_means(buf, h_luma, h_chroma, search_radius, patch_radius),
+            &Processing::WaveletDenoise(threshold_luma, threshold_chroma, layers) => denoise::wavelet(buf, threshold_luma, threshold_chroma, layers),
+            &Processing::NeutralizeBackground(threshold) => calibration::neutralize_background(buf, threshold),
+            &Processing::WhiteBalance(reference) => calibration::white_balance(buf, reference),
+            &Processing::AlignChannels(threshold) => calibration::align_channels(buf, threshold),
             &Processing::BGone(threshold) => background_extract(buf, threshold),
             &Processing::BlackWhite(threshold) => black_while(buf, threshold),
             &Processing::Akaze(threshold) => akaze_draw(buf, threshold),
@@ -23,57 +35,39 @@ pub fn process(buf: &mut Rgb64FImage, num_files: usize, processing: &[Processing
     }
 }
 
-pub fn average(buf: &mut Rgb64FImage, num_files: usize) {
-    for pixel in buf.pixels_mut() {
-        pixel.0[0] /= num_files as f64;
-        pixel.0[1] /= num_files as f64;
-        pixel.0[2] /= num_files as f64;
+pub fn average<P: FloatPixel>(buf: &mut FloatImage<P>, num_files: usize) {
+    for value in buf.iter_mut() {
+        *value /= num_files as f64;
     }
 }
 
-pub fn maxcol(buf: &Rgb64FImage) -> f64 {
-    buf.pixels()
-        .fold(0_f64, |maxcol, px| {
-            let maxcol = maxcol.max(px.0[0]);
-            let maxcol = maxcol.max(px.0[1]);
-            let maxcol = maxcol.max(px.0[2]);
-            maxcol
-        })
+pub fn maxcol<P: FloatPixel>(buf: &FloatImage<P>) -> f64 {
+    buf.iter().fold(0_f64, |maxcol, &value| maxcol.max(value))
 }
-pub fn maxscale(buf: &mut Rgb64FImage) {
+pub fn maxscale<P: FloatPixel>(buf: &mut FloatImage<P>) {
     let maxcol = maxcol(buf);
-    for pixel in buf.pixels_mut() {
-        pixel.0[0] /= maxcol;
-        pixel.0[1] /= maxcol;
-        pixel.0[2] /= maxcol;
-    }
+    maxscale_fixed(buf, maxcol);
 }
 
-pub fn maxscale_fixed(buf: &mut Rgb64FImage, maxcol: f64) {
-    for pixel in buf.pixels_mut() {
-        pixel.0[0] /= maxcol;
-        pixel.0[1] /= maxcol;
-        pixel.0[2] /= maxcol;
+pub fn maxscale_fixed<P: FloatPixel>(buf: &mut FloatImage<P>, maxcol: f64) {
+    for value in buf.iter_mut() {
+        *value /= maxcol;
     }
 }
 
-pub fn sqrt(buf: &mut Rgb64FImage) {
-    for pixel in buf.pixels_mut() {
-        pixel.0[0] = pixel.0[0].sqrt();
-        pixel.0[1] = pixel.0[1].sqrt();
-        pixel.0[2] = pixel.0[2].sqrt();
+pub fn sqrt<P: FloatPixel>(buf: &mut FloatImage<P>) {
+    for value in buf.iter_mut() {
+        *value = value.sqrt();
     }
 }
 
-pub fn asinh(buf: &mut Rgb64FImage) {
-    for pixel in buf.pixels_mut() {
-        pixel.0[0] = pixel.0[0].asinh();
-        pixel.0[1] = pixel.0[1].asinh();
-        pixel.0[2] = pixel.0[2].asinh();
+pub fn asinh<P: FloatPixel>(buf: &mut FloatImage<P>) {
+    for value in buf.iter_mut() {
+        *value = value.asinh();
     }
 }
 
-pub fn sharpen(buf: &mut Rgb64FImage) {
+pub fn sharpen<P: FloatPixel>(buf: &mut FloatImage<P>) {
     *buf = imageops::filter3x3(buf, &[
          0., -1.,  0.,
         -1.,  5., -1.,
@@ -81,7 +75,38 @@ pub fn sharpen(buf: &mut Rgb64FImage) {
     ]);
 }
 
-pub fn median(buf: &mut Rgb64FImage, radius: u32) {
+/// Add the difference to the blurred image where it exceeds the threshold.
+///
+/// With `luminance` the difference is calculated on the luminance and added to all channels equally.
+/// With a `mask` threshold only the object is sharpened, fading out towards the background.
+pub fn unsharp_mask<P: FloatPixel>(buf: &mut FloatImage<P>, radius: f32, amount: f64, threshold: f64, luminance: bool, mask: Option<f64>) {
+    let mut blurred = buf.clone();
+    gaussian_blur(&mut blurred, radius);
+    let luma = helpers::luma::<P>;
+    for (pixel, blurred) in buf.pixels_mut().zip(blurred.pixels()) {
+        let weight = match mask {
+            Some(mask) => (luma(blurred) / mask).clamp(0., 1.),
+            None => 1.,
+        };
+        let sharpen = |value: f64, diff: f64| if diff.abs() > threshold {
+            value + weight * amount * diff
+        } else {
+            value
+        };
+        if luminance {
+            let diff = luma(pixel) - luma(blurred);
+            for c in pixel.channels_mut() {
+                *c = sharpen(*c, diff);
+            }
+        } else {
+            for (c, &b) in pixel.channels_mut().iter_mut().zip(blurred.channels()) {
+                *c = sharpen(*c, *c - b);
+            }
+        }
+    }
+}
+
+pub fn median<P: FloatPixel>(buf: &mut FloatImage<P>, radius: u32) {
     // prepare bitmap
     let radius: i32 = radius.try_into().unwrap();
     let side_len = radius as usize * 2 + 1;
@@ -95,75 +120,72 @@ pub fn median(buf: &mut Rgb64FImage, radius: u32) {
     }
 
     let orig = buf.clone();
-    let mut reds = Vec::with_capacity(side_len * side_len);
-    let mut greens = Vec::with_capacity(side_len * side_len);
-    let mut blues = Vec::with_capacity(side_len * side_len);
-    for (x, y, pixel) in buf.enumerate_pixels_mut() {
-        reds.clear();
-        greens.clear();
-        blues.clear();
-        for dy in -radius..=radius {
-            for dx in -radius..=radius {
-                let index = (dy + radius) as usize * side_len + (dx + radius) as usize;
-                if !bitmap[index] {
-                    continue;
+    let width = orig.width() as usize;
+    let channels = P::CHANNEL_COUNT as usize;
+    buf.par_chunks_mut(width * channels).enumerate().for_each(|(y, row)| {
+        let mut values = vec![Vec::with_capacity(side_len * side_len); channels];
+        for (x, pixel) in row.chunks_mut(channels).enumerate() {
+            values.iter_mut().for_each(Vec::clear);
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let index = (dy + radius) as usize * side_len + (dx + radius) as usize;
+                    if !bitmap[index] {
+                        continue;
+                    }
+                    let x = (x as i32 + dx).max(0).min(orig.width() as i32 - 1) as u32;
+                    let y = (y as i32 + dy).max(0).min(orig.height() as i32 - 1) as u32;
+                    for (values, &value) in values.iter_mut().zip(orig[(x, y)].channels()) {
+                        values.push(NotNan::new(value).unwrap());
+                    }
                 }
-                let x = (x as i32 + dx).max(0).min(orig.width() as i32 - 1) as u32;
-                let y = (y as i32 + dy).max(0).min(orig.height() as i32 - 1) as u32;
-                reds.push(NotNan::new(orig[(x, y)].0[0]).unwrap());
-                greens.push(NotNan::new(orig[(x, y)].0[1]).unwrap());
-                blues.push(NotNan::new(orig[(x, y)].0[2]).unwrap());
+            }
+            for (value, values) in pixel.iter_mut().zip(&mut values) {
+                values.sort();
+                *value = values[values.len() / 2].into_inner();
             }
         }
-        reds.sort();
-        greens.sort();
-        blues.sort();
-        let red = reds[reds.len() / 2].into_inner();
-        let green = greens[greens.len() / 2].into_inner();
-        let blue = blues[blues.len() / 2].into_inner();
-        *pixel = Rgb([red, green, blue]);
-    }
+    });
 }
 
-pub fn sobel(buf: &mut Rgb64FImage, blur: i32) {
-    let sobeled = crate::helpers::edgy_sobel(&DynamicImage::ImageRgb64F(buf.clone()), blur);
-    *buf = DynamicImage::ImageLuma8(sobeled).into_rgb64f();
+pub fn sobel<P: FloatPixel>(buf: &mut FloatImage<P>, blur: i32) {
+    let sobeled = crate::helpers::edgy_sobel(&P::to_dynamic(buf), blur);
+    *buf = P::from_dynamic(DynamicImage::ImageLuma8(sobeled));
 }
 
-pub fn gaussian_blur(buf: &mut Rgb64FImage, sigma: f32) {
+pub fn gaussian_blur<P: FloatPixel>(buf: &mut FloatImage<P>, sigma: f32) {
     *buf = image::imageops::blur(buf, sigma)
 }
 
-pub fn background_extract(buf: &mut Rgb64FImage, threshold: f64) {
+pub fn background_extract<P: FloatPixel>(buf: &mut FloatImage<P>, threshold: f64) {
     for pixel in buf.pixels_mut() {
-        let value = pixel.0.into_iter().sum::<f64>() / 3.;
+        let value = helpers::luma(pixel);
         if value < threshold {
-            *pixel = Rgb([0., 0., 0.]);
+            pixel.channels_mut().fill(0.);
         }
     }
 }
 
-pub fn black_while(buf: &mut Rgb64FImage, threshold: f64) {
+pub fn black_while<P: FloatPixel>(buf: &mut FloatImage<P>, threshold: f64) {
     for pixel in buf.pixels_mut() {
-        let value = pixel.0.into_iter().sum::<f64>() / 3.;
+        let value = helpers::luma(pixel);
         if value < threshold {
-            *pixel = Rgb([0., 0., 0.]);
+            pixel.channels_mut().fill(0.);
         } else {
-            *pixel = Rgb([1., 1., 1.]);
+            pixel.channels_mut().fill(1.);
         }
     }
 }
 
-pub fn single_object_detection(buf: &mut Rgb64FImage, threshold: f64) {
+pub fn single_object_detection<P: FloatPixel>(buf: &mut FloatImage<P>, threshold: f64) {
     let sod = register::single_object_detection(buf, threshold);
     helpers::draw_object(buf, sod);
 }
-pub fn average_brightness_alignment(buf: &mut Rgb64FImage, threshold: f64) {
+pub fn average_brightness_alignment<P: FloatPixel>(buf: &mut FloatImage<P>, threshold: f64) {
     let aba = register::average_brightness(buf, threshold);
     helpers::draw_cross(buf, (aba.middlex, aba.middley));
 }
 
-pub fn akaze_draw(buf: &mut Rgb64FImage, threshold: f64) {
+pub fn akaze_draw<P: FloatPixel>(buf: &mut FloatImage<P>, threshold: f64) {
     let akaze = register::akaze(buf, threshold);
     for keypoint in akaze.keypoints {
         helpers::akaze_draw_kp(buf, keypoint);
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use crate::helpers::{self, FloatImage, FloatPixel};
+use crate::register;
+
+/// Number of the brightest stars the FWHM is measured on
+const FWHM_STARS: usize = 20;
+/// Radius of the aperture the FWHM of a star is measured in
+const FWHM_RADIUS: i64 = 4;
+
+/// Statistics of a frame used to reject frames of bad quality
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct FrameStatistics {
+    /// mean luminance
+    pub brightness: f32,
+    /// median luminance
+    pub background: f32,
+    /// number of local maxima brighter than five sigma of the noise above the background
+    pub stars: u32,
+    /// median full width at half maximum of the brightest stars in pixels
+    pub fwhm: Option<f32>,
+    /// variance of the laplacian of the luminance
+    pub sharpness: f32,
+}
+
+pub fn measure<P: FloatPixel>(buf: &FloatImage<P>) -> FrameStatistics {
+    let width = buf.width() as i64;
+    let height = buf.height() as i64;
+    let luma: Vec<f32> = buf.pixels().map(|pixel| helpers::luma(pixel) as f32).collect();
+    let at = |x: i64, y: i64| luma[(y.max(0).min(height - 1) * width + x.max(0).min(width - 1)) as usize];
+
+    let brightness = luma.iter().sum::<f32>() / luma.len() as f32;
+    let background = register::median(luma.clone()).unwrap_or_default();
+    let noise = register::median(luma.iter().map(|v| (v - background).abs()).collect()).unwrap_or_default() * 1.4826;
+
+    // stars
+    let threshold = background + 5. * noise.max(f32::EPSILON);
+    let mut stars: Vec<(i64, i64, f32)> = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let value = at(x, y);
+            if value < threshold {
+                continue;
+            }
+            let local_maximum = (-1..=1).all(|dy| (-1..=1).all(|dx| (dx, dy) == (0, 0) || at(x + dx, y + dy) < value));
+            if local_maximum {
+                stars.push((x, y, value));
+            }
+        }
+    }
+
+    // FWHM from the second moment of the background-subtracted brightest stars
+    stars.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+    let fwhms: Vec<f32> = stars.iter().take(FWHM_STARS).filter_map(|&(x, y, _)| {
+        let mut sum = 0.;
+        let mut moment = 0.;
+        for dy in -FWHM_RADIUS..=FWHM_RADIUS {
+            for dx in -FWHM_RADIUS..=FWHM_RADIUS {
+                let value = (at(x + dx, y + dy) - background).max(0.);
+                sum += value;
+                moment += value * (dx * dx + dy * dy) as f32;
+            }
+        }
+        (sum > 0.).then(|| 2.3548 * (moment / sum / 2.).sqrt())
+    }).collect();
+
+    // sharpness
+    let laplacian: Vec<f32> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4. * at(x, y))
+        .collect();
+    let mean = laplacian.iter().sum::<f32>() / laplacian.len() as f32;
+    let sharpness = laplacian.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / laplacian.len() as f32;
+
+    FrameStatistics {
+        brightness,
+        background,
+        stars: stars.len() as u32,
+        fwhm: register::median(fwhms),
+        sharpness,
+    }
+}
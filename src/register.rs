@@ -4,22 +4,32 @@ use std::sync::atomic::{AtomicU32, Ordering};
 use cv::bitarray::BitArray;
 use cv::feature::akaze::{Akaze, KeyPoint};
 use either::Either;
-use image::{DynamicImage, ImageBuffer, Luma, Rgb64FImage};
-use image::buffer::ConvertBuffer;
+use image::{DynamicImage, Luma, Rgb};
 use plotters::backend::BitMapBackend;
 use plotters::chart::ChartBuilder;
 use plotters::drawing::IntoDrawingArea;
 use plotters::element::Circle;
-use plotters::style::{BLUE, Color, GREEN, RED, WHITE};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use plotters::style::{BLACK, BLUE, Color, GREEN, RED, WHITE};
+use rayon::iter::ParallelIterator;
 use serde::{Serialize, Deserialize};
-use crate::{CommonArgs, helpers, processing, Register};
+use crate::{CommonArgs, helpers, processing, quality, Register, RegistrationMethod, smoothing, source};
+use crate::helpers::{FloatImage, FloatPixel};
+use crate::loader::Loader;
+use crate::quality::FrameStatistics;
 
 pub fn register(common: CommonArgs, register: Register) {
-    let CommonArgs { colorspace, num_files, skip_files } = common;
-    let Register { imagepaths, reference_image, preprocessing_akaze, preprocessing_rest, outfile, akaze, single_object_detection, average_brightness_alignment } = register;
+    if common.mono {
+        register_generic::<Luma<f64>>(common, register)
+    } else {
+        register_generic::<Rgb<f64>>(common, register)
+    }
+}
+
+fn register_generic<P: FloatPixel>(common: CommonArgs, register: Register) {
+    let CommonArgs { colorspace, num_files, skip_files, mono: _, max_memory: _, prefetch: _ } = common;
+    let Register { imagepaths, reference_image, preprocessing_akaze, preprocessing_rest, outfile, akaze, single_object_detection, average_brightness_alignment, disk, smooth_window, snap, interpolate_akaze } = register;
 
-    let mut files: Vec<_> = imagepaths.into_iter()
+    let files: Vec<_> = imagepaths.into_iter()
         .flat_map(|path| {
             if path.is_dir() {
                 Either::Left(path.read_dir().unwrap().map(|entry| entry.unwrap().path()))
@@ -29,6 +39,10 @@ pub fn register(common: CommonArgs, register: Register) {
                 panic!("input path {} is neither directory nor file", path.display())
             }
         }).collect();
+    // frames of videos are addressed by their index
+    let mut files: Vec<_> = files.into_iter()
+        .flat_map(|path| if source::is_video(&path) { source::video_frames(&path) } else { vec![path] })
+        .collect();
     files.sort_by_key(|path| path.file_name().unwrap().to_owned());
     let files: Vec<_> = files.into_iter()
         .skip(skip_files)
@@ -36,14 +50,19 @@ pub fn register(common: CommonArgs, register: Register) {
         .collect();
 
     // akaze reference image
-    let mut reference_image_akaze = helpers::load_image(&files[reference_image], colorspace);
+    let mut reference_image_akaze = helpers::load_image::<P, _>(&files[reference_image], colorspace);
     processing::process(&mut reference_image_akaze, num_files, &preprocessing_akaze);
     let reference_akaze_data = akaze.map(|akaze| (akaze, self::akaze(&reference_image_akaze, akaze)));
 
+    // per thread: the frame and the largest of its akaze-preprocessed copy with an intermediate processing
+    // result, the luminance planes of the statistics, or the edge image of the disk fit with the two
+    // intermediate frames of the sobel filter
+    let frames_per_worker = if disk.is_some() { 4 } else { 3 };
+    let loader = Loader::new::<P>(&common, reference_image_akaze.width(), reference_image_akaze.height(), frames_per_worker, 1);
     let counter = AtomicU32::new(0);
-    let image_registrations: Vec<_> = files.into_par_iter()
-        .map(|path| (helpers::load_image(&path, colorspace), path))
-        .map(|(image, path)| {
+    let files: Vec<_> = files.into_iter().enumerate().collect();
+    let mut image_registrations: Vec<_> = loader.par_load::<P, _, _>(files, |(_, path)| path, |frames| frames
+        .map(|((index, path), image)| {
             let count = counter.fetch_add(1, Ordering::Relaxed);
             if count % 50 == 0 {
                 println!("{count}");
@@ -52,23 +71,38 @@ pub fn register(common: CommonArgs, register: Register) {
                 let mut preprocessed = image.clone();
                 processing::process(&mut preprocessed, num_files, &preprocessing_akaze);
                 let akaze_data = self::akaze(&preprocessed, *akaze);
-                akaze_data.akaze_registration(reference_akaze_data, reference_image_akaze.width(), reference_image_akaze.height())
+                akaze_data.akaze_registration_with_confidence(reference_akaze_data, reference_image_akaze.width(), reference_image_akaze.height())
             });
+            let (akaze, akaze_confidence) = akaze.unzip();
+            let statistics = quality::measure(&image);
+            let disk_registration = disk.and_then(|threshold| disk_fit(&image, threshold));
             let mut preprocessed = image;
             processing::process(&mut preprocessed, num_files, &preprocessing_rest);
             let (sod, aba) = sod_aba(&preprocessed, single_object_detection, average_brightness_alignment);
-            ImageRegistration {
+            let timestamp = source::timestamp(&path);
+            (index, ImageRegistration {
                 image: path,
                 akaze,
+                akaze_confidence,
                 sod,
                 aba,
-            }
-        }).collect();
+                disk: disk_registration,
+                fused: None,
+                smoothed: None,
+                statistics: Some(statistics),
+                timestamp,
+            })
+        }).collect());
+    // frames are processed out of order
+    image_registrations.sort_by_key(|&(index, _)| index);
+    let image_registrations = image_registrations.into_iter().map(|(_, reg)| reg).collect();
 
-    let reg = Registration {
+    let mut reg = Registration {
         reference_image,
         images: image_registrations,
     };
+    fuse(&mut reg);
+    smoothing::smooth(&mut reg, smooth_window, snap, interpolate_akaze);
     helpers::save_registration(outfile, &reg);
     statistics(&reg);
 }
@@ -82,8 +116,25 @@ pub struct Registration {
 pub struct ImageRegistration {
     pub image: PathBuf,
     pub akaze: Option<AkazeRegistration>,
+    #[serde(default)]
+    pub akaze_confidence: Option<AkazeConfidence>,
     pub sod: SodRegistration,
     pub aba: AbaRegistration,
+    /// circle fitted to the limb, `None` for frames without a disk or old registrations
+    #[serde(default)]
+    pub disk: Option<DiskRegistration>,
+    /// robust combination of the offsets of all methods, see [`fuse`]
+    #[serde(default)]
+    pub fused: Option<(f32, f32)>,
+    /// fused offset snapped to the smoothed drift trajectory if it deviates too much, see [`smoothing::smooth`]
+    #[serde(default)]
+    pub smoothed: Option<(f32, f32)>,
+    /// statistics of the unprocessed frame
+    #[serde(default)]
+    pub statistics: Option<FrameStatistics>,
+    /// capture time in seconds since the Unix epoch, see [`source::timestamp`]
+    #[serde(default)]
+    pub timestamp: Option<f64>,
 }
 impl ImageRegistration {
     pub fn offsets(&self, reference: &ImageRegistration) -> ((i32, i32), (i32, i32), (i32, i32)) {
@@ -93,31 +144,104 @@ impl ImageRegistration {
             self.aba.offset(&reference.aba),
         )
     }
+    /// Sub-pixel offsets of akaze (if not rejected), sod, aba and disk (if fitted)
+    pub fn method_offsets(&self, reference: &ImageRegistration) -> [Option<(f32, f32)>; 4] {
+        let (sodx, sody) = self.sod.offset(&reference.sod);
+        [
+            match self.akaze {
+                Some(AkazeRegistration::Offset(dx, dy)) => Some((dx, dy)),
+                _ => None,
+            },
+            Some((sodx as f32, sody as f32)),
+            Some((reference.aba.middlex - self.aba.middlex, reference.aba.middley - self.aba.middley)),
+            self.disk.zip(reference.disk).map(|(disk, reference)| disk.offset(&reference)),
+        ]
+    }
+    /// Unrounded offset of the given method, see [`offset`](Self::offset)
+    pub fn subpixel_offset(&self, reference: &ImageRegistration, method: RegistrationMethod) -> Option<(f32, f32)> {
+        let [_, sod, aba, disk] = self.method_offsets(reference);
+        match (method, self.akaze) {
+            (RegistrationMethod::Fused, _) => self.fused,
+            (RegistrationMethod::Smoothed, _) => self.smoothed,
+            (RegistrationMethod::Akaze, Some(AkazeRegistration::Offset(dx, dy) | AkazeRegistration::Interpolated(dx, dy))) => Some((dx, dy)),
+            (RegistrationMethod::Best, Some(AkazeRegistration::Offset(dx, dy))) => Some((dx, dy)),
+            (RegistrationMethod::Akaze, _) => None,
+            (RegistrationMethod::Sod, _) => sod,
+            (RegistrationMethod::Disk, _) => disk,
+            (RegistrationMethod::Aba | RegistrationMethod::Best, _) => aba,
+        }
+    }
+    /// Offset of the given method, or `None` if AKAZE is missing or rejected, no disk was fitted or the registration
+    /// wasn't fused or smoothed
+    pub fn offset(&self, reference: &ImageRegistration, method: RegistrationMethod) -> Option<(i32, i32)> {
+        match (method, self.akaze) {
+            (RegistrationMethod::Fused, _) => self.fused.map(|(dx, dy)| (dx.round() as i32, dy.round() as i32)),
+            (RegistrationMethod::Smoothed, _) => self.smoothed.map(|(dx, dy)| (dx.round() as i32, dy.round() as i32)),
+            (RegistrationMethod::Akaze, Some(akaze @ (AkazeRegistration::Offset(..) | AkazeRegistration::Interpolated(..)))) => Some(akaze.offset()),
+            (RegistrationMethod::Best, Some(akaze @ AkazeRegistration::Offset(..))) => Some(akaze.offset()),
+            (RegistrationMethod::Akaze, _) => None,
+            (RegistrationMethod::Sod, _) => Some(self.sod.offset(&reference.sod)),
+            (RegistrationMethod::Disk, _) => self.subpixel_offset(reference, method).map(|(dx, dy)| (dx.round() as i32, dy.round() as i32)),
+            (RegistrationMethod::Aba | RegistrationMethod::Best, _) => Some(self.aba.offset(&reference.aba)),
+        }
+    }
 }
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum AkazeRegistration {
     Offset(f32, f32),
     Rejected,
+    /// rejected, but interpolated from the smoothed trajectory of neighbouring frames
+    Interpolated(f32, f32),
 }
 impl AkazeRegistration {
     pub fn offset(&self) -> (i32, i32) {
         match self {
             AkazeRegistration::Rejected => (0, 0),
-            AkazeRegistration::Offset(dx, dy) => (dx.round() as i32, dy.round() as i32)
+            AkazeRegistration::Offset(dx, dy) | AkazeRegistration::Interpolated(dx, dy) => (dx.round() as i32, dy.round() as i32)
         }
     }
 }
+/// How well the AKAZE registration is supported by the matched keypoints
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct AkazeConfidence {
+    /// number of keypoints found in the frame
+    pub keypoints: usize,
+    /// number of keypoints matched with the reference
+    pub matches: usize,
+    /// number of matches left after rejecting the ones deviating from the median direction
+    pub inliers: usize,
+    /// standard deviation of the displacement of the inliers
+    pub std_dx: f32,
+    pub std_dy: f32,
+}
+impl AkazeConfidence {
+    pub fn inlier_ratio(&self) -> f32 {
+        if self.matches == 0 {
+            return 0.;
+        }
+        self.inliers as f32 / self.matches as f32
+    }
+}
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct SodRegistration {
     pub left: u32,
     pub right: u32,
     pub top: u32,
     pub bottom: u32,
+    /// number of pixels above the threshold
+    #[serde(default)]
+    pub pixels: u32,
 }
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct AbaRegistration {
     pub middlex: f32,
     pub middley: f32,
+    /// number of pixels above the threshold
+    #[serde(default)]
+    pub pixels: u32,
+    /// summed brightness of the pixels above the threshold
+    #[serde(default)]
+    pub signal: f64,
 }
 impl SodRegistration {
     pub fn middle(&self) -> (u32, u32) {
@@ -131,22 +255,114 @@ impl SodRegistration {
     pub fn height(&self) -> u32 {
         self.bottom - self.top
     }
+    /// Fraction of the bounding box covered by pixels above the threshold.
+    ///
+    /// Noise or multiple objects spanning a large bounding box result in a low fill.
+    pub fn fill(&self) -> f32 {
+        let area = (self.width() as f32 + 1.) * (self.height() as f32 + 1.);
+        self.pixels as f32 / area
+    }
     pub fn offset(&self, reference: &SodRegistration) -> (i32, i32) {
         let (x1, y1) = reference.middle();
         let (x2, y2) = self.middle();
         (x1 as i32 - x2 as i32, y1 as i32 - y2 as i32)
     }
 }
+/// Circle fitted to the limb of a planetary disk, see [`disk_fit`]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct DiskRegistration {
+    pub middlex: f32,
+    pub middley: f32,
+    pub radius: f32,
+    /// number of edge points on the fitted circle
+    pub inliers: u32,
+}
+impl DiskRegistration {
+    pub fn offset(&self, reference: &DiskRegistration) -> (f32, f32) {
+        (reference.middlex - self.middlex, reference.middley - self.middley)
+    }
+}
 impl AbaRegistration {
     pub fn offset(&self, reference: &AbaRegistration) -> (i32, i32) {
-        let AbaRegistration { middlex: x1, middley: y1 } = reference;
-        let AbaRegistration { middlex: x2, middley: y2 } = self;
+        let AbaRegistration { middlex: x1, middley: y1, .. } = reference;
+        let AbaRegistration { middlex: x2, middley: y2, .. } = self;
         (x1.round() as i32 - x2.round() as i32, y1.round() as i32 - y2.round() as i32)
     }
 }
 
+/// Fuse the offsets of all methods into a single offset per frame.
+///
+/// The noise of each method is estimated across the session from its deviation to the per-frame
+/// median of all methods. Per frame, offsets deviating more than three sigma from the median are
+/// discarded as outliers and the remaining ones are averaged weighted by their inverse variance.
+fn fuse(reg: &mut Registration) {
+    let reference = reg.images[reg.reference_image].clone();
+    let offsets: Vec<_> = reg.images.iter().map(|image| image.method_offsets(&reference)).collect();
+    let medians: Vec<_> = offsets.iter()
+        .map(|offsets| median_offset(offsets.iter().flatten().copied()))
+        .collect();
+    let distance = |(x1, y1): (f32, f32), (x2, y2): (f32, f32)| ((x1 - x2).powi(2) + (y1 - y2).powi(2)).sqrt();
+
+    // robust standard deviation from the median absolute deviation, at least half a pixel due to rounding
+    let sigmas: [f32; 4] = std::array::from_fn(|method| {
+        let deviations: Vec<f32> = offsets.iter().zip(&medians)
+            .filter_map(|(offsets, &median)| Some(distance(offsets[method]?, median)))
+            .collect();
+        median(deviations).map_or(f32::INFINITY, |mad| (mad * 1.4826).max(0.5))
+    });
+    println!("registration sigma akaze {:.2}, sod {:.2}, aba {:.2}, disk {:.2}", sigmas[0], sigmas[1], sigmas[2], sigmas[3]);
+
+    for ((image, offsets), median) in reg.images.iter_mut().zip(&offsets).zip(medians) {
+        let inliers: Vec<_> = offsets.iter().zip(sigmas)
+            .filter_map(|(&offset, sigma)| Some((offset?, sigma)))
+            .filter(|&(offset, sigma)| distance(offset, median) <= 3. * sigma)
+            .collect();
+        image.fused = Some(if inliers.is_empty() {
+            median
+        } else {
+            let weights: f32 = inliers.iter().map(|(_, sigma)| sigma.powi(-2)).sum();
+            let (x, y) = inliers.iter().fold((0., 0.), |(x, y), &((dx, dy), sigma)| {
+                (x + dx * sigma.powi(-2), y + dy * sigma.powi(-2))
+            });
+            (x / weights, y / weights)
+        });
+    }
+}
+
+pub fn median_offset(offsets: impl Iterator<Item = (f32, f32)>) -> (f32, f32) {
+    let (xs, ys): (Vec<_>, Vec<_>) = offsets.unzip();
+    (median(xs).unwrap_or_default(), median(ys).unwrap_or_default())
+}
+
+/// Median, averaging the two middle values of an even number of values
+pub fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f32::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len() % 2 == 1 {
+        values[mid]
+    } else {
+        (values[mid - 1] + values[mid]) / 2.
+    })
+}
+
 fn statistics(reg: &Registration) {
     let reference = &reg.images[reg.reference_image];
+    let median_of = |f: &dyn Fn(&ImageRegistration) -> Option<f32>| median(reg.images.iter().filter_map(f).collect());
+    if let Some(inliers) = median_of(&|image| Some(image.akaze_confidence?.inliers as f32)) {
+        println!(
+            "akaze median: {inliers} inliers, inlier ratio {:.2}, displacement std-dev {:.2}",
+            median_of(&|image| Some(image.akaze_confidence?.inlier_ratio())).unwrap_or_default(),
+            median_of(&|image| image.akaze_confidence.map(|c| c.std_dx.hypot(c.std_dy))).unwrap_or_default(),
+        );
+    }
+    println!(
+        "sod median fill {:.2}, aba median signal {:.1}",
+        median_of(&|image| Some(image.sod.fill())).unwrap_or_default(),
+        median_of(&|image| Some(image.aba.signal as f32)).unwrap_or_default(),
+    );
     let (maxabsx, maxabsy) = reg.images.iter()
         .fold((i32::MIN, i32::MIN), |(maxabsx, maxabsy), reg| {
             let ((dx1, dy1), (dx2, dy2), (dx3, dy3)) = reg.offsets(reference);
@@ -172,10 +388,12 @@ fn statistics(reg: &Registration) {
         reg.images.iter()
             .flat_map(|reg| {
                 let (a, b, c) = reg.offsets(reference);
+                let d = reg.fused.unwrap_or_default();
                 [
                     ((a.0 as f32, a.1 as f32), RED),
                     ((b.0 as f32 + 0.3, b.1 as f32), GREEN),
                     ((c.0 as f32, c.1 as f32 + 0.3), BLUE),
+                    (d, BLACK),
                 ]
             }).map(|((x, y), col)| Circle::new((x, y), 2, col.filled())),
     ).unwrap();
@@ -209,11 +427,6 @@ impl Match {
 }
 
 impl AkazeData {
-    pub fn matches(&self, other: &AkazeData, width: u32, height: u32) -> Vec<Match> {
-        let mut matches = self.matches_unrejected(other);
-        Self::reject_matches(&mut matches, width, height);
-        matches
-    }
     pub fn matches_unrejected(&self, other: &AkazeData) -> Vec<Match> {
         const THRESH: f32 = 0.8;
 
@@ -250,69 +463,234 @@ impl AkazeData {
         // });
 
         // reject everything deviating >5° from the median
+        if matches.is_empty() {
+            return;
+        }
         matches.sort_by(|m1, m2| m1.arc().total_cmp(&m2.arc()));
         let median_arcdeg = matches[matches.len() / 2].arcdeg();
         matches.retain(|m| (median_arcdeg - m.arcdeg()).abs() <= 5);
     }
 
     pub fn akaze_registration(&self, reference: &AkazeData, width: u32, height: u32) -> AkazeRegistration {
-        let matches = reference.matches(self, width, height);
+        self.akaze_registration_with_confidence(reference, width, height).0
+    }
+
+    pub fn akaze_registration_with_confidence(&self, reference: &AkazeData, width: u32, height: u32) -> (AkazeRegistration, AkazeConfidence) {
+        let mut matches = reference.matches_unrejected(self);
+        let unrejected = matches.len();
+        Self::reject_matches(&mut matches, width, height);
+        let mut confidence = AkazeConfidence {
+            keypoints: self.keypoints.len(),
+            matches: unrejected,
+            inliers: matches.len(),
+            std_dx: 0.,
+            std_dy: 0.,
+        };
         if matches.is_empty() {
-            return AkazeRegistration::Rejected;
+            return (AkazeRegistration::Rejected, confidence);
         }
 
         // average all resulting offsets
-        let matches_len = matches.len();
-        let (dx, dy) = matches.into_iter().fold((0., 0.), |(dx, dy), m| (dx+m.dx(), dy+m.dy()));
-        AkazeRegistration::Offset(dx / matches_len as f32, dy / matches_len as f32)
+        let matches_len = matches.len() as f32;
+        let (dx, dy) = matches.iter().fold((0., 0.), |(dx, dy), m| (dx+m.dx(), dy+m.dy()));
+        let (dx, dy) = (dx / matches_len, dy / matches_len);
+        let (var_dx, var_dy) = matches.iter().fold((0., 0.), |(vx, vy), m| (vx + (m.dx() - dx).powi(2), vy + (m.dy() - dy).powi(2)));
+        confidence.std_dx = (var_dx / matches_len).sqrt();
+        confidence.std_dy = (var_dy / matches_len).sqrt();
+        (AkazeRegistration::Offset(dx, dy), confidence)
     }
 }
 
-pub fn akaze(buf: &Rgb64FImage, threshold: f64) -> AkazeData {
+pub fn akaze<P: FloatPixel>(buf: &FloatImage<P>, threshold: f64) -> AkazeData {
     let detector = Akaze::new(threshold);
-    let luma16: ImageBuffer<Luma<u16>, Vec<u16>> = buf.convert();
+    let luma16 = P::to_luma16(buf);
     let (key_points, descriptions) = detector.extract(&DynamicImage::ImageLuma16(luma16));
     AkazeData { keypoints: key_points, descriptions }
 }
 
-pub fn sod_aba(buf: &Rgb64FImage, threshold_sod: f64, threshold_aba: f64) -> (SodRegistration, AbaRegistration) {
+pub fn sod_aba<P: FloatPixel>(buf: &FloatImage<P>, threshold_sod: f64, threshold_aba: f64) -> (SodRegistration, AbaRegistration) {
     let mut left = u32::MAX;
     let mut right = 0;
     let mut top = u32::MAX;
     let mut bottom = 0;
+    let mut sod_pixels = 0;
+    let mut aba_pixels = 0;
     let mut sum = 0.0;
     let mut weighted_sum_rows = 0.0;
     let mut weighted_sum_columns = 0.0;
 
     for (x, y, pixel) in buf.enumerate_pixels() {
-        let value = pixel.0.into_iter().sum::<f64>() / 3.;
+        let value = helpers::luma(pixel);
         // single object detection
         if value >= threshold_sod {
             left = x.min(left);
             right = x.max(right);
             top = y.min(top);
             bottom = y.max(bottom);
+            sod_pixels += 1;
         }
         // average brightness alignment
         if value >= threshold_aba {
+            aba_pixels += 1;
             sum += value;
             weighted_sum_rows += y as f64 * value;
             weighted_sum_columns += x as f64 * value;
         }
     }
-    let sod = SodRegistration { left, right, top, bottom };
+    let sod = SodRegistration { left, right, top, bottom, pixels: sod_pixels };
 
     let middlex = (weighted_sum_columns / sum) as f32;
     let middley = (weighted_sum_rows / sum) as f32;
-    let aba = AbaRegistration { middlex, middley };
+    let aba = AbaRegistration { middlex, middley, pixels: aba_pixels, signal: sum };
 
     (sod, aba)
 }
 
-pub fn single_object_detection(buf: &Rgb64FImage, threshold: f64) -> SodRegistration {
+/// Fit a circle to the limb of a planetary disk.
+///
+/// Edge points are the pixels of the sobel image of the maxscaled frame above `threshold` times the
+/// strongest edge. RANSAC finds the circle through three of them supported by the most points, which
+/// keeps the terminator and surface features from pulling the fit, and a least squares fit to the
+/// supporting points refines it to sub-pixel precision. `None` if the circle isn't supported along a large
+/// enough part of its circumference or by many more points than the annuli just inside and outside of it,
+/// as random circles through the edges of star fields or noise are.
+pub fn disk_fit<P: FloatPixel>(buf: &FloatImage<P>, threshold: f64) -> Option<DiskRegistration> {
+    const ITERATIONS: usize = 500;
+    const MIN_INLIERS: usize = 20;
+    // supporting points per pixel of circumference, a crescent or gibbous limb covers half of it
+    const MIN_COVERAGE: f32 = 0.3;
+    // supporting points relative to the points of the neighbouring annuli
+    const MIN_CONTRAST: usize = 4;
+    // distance of a point from the circle to still support it
+    const TOLERANCE: f64 = 1.5;
+
+    let mut edges = buf.clone();
+    processing::maxscale(&mut edges);
+    processing::sobel(&mut edges, 1);
+    let strongest = edges.pixels().map(helpers::luma).fold(0., f64::max);
+    if strongest <= 0. {
+        return None;
+    }
+    // the sobel image lacks the outermost pixels of the frame
+    let points: Vec<(f64, f64)> = edges.enumerate_pixels()
+        .filter(|(_, _, pixel)| helpers::luma(*pixel) >= threshold * strongest)
+        .map(|(x, y, _)| (x as f64 + 1., y as f64 + 1.))
+        .collect();
+    if points.len() < MIN_INLIERS {
+        return None;
+    }
+    let max_radius = buf.width().max(buf.height()) as f64;
+    let inliers = |(x, y, r): (f64, f64, f64)| -> Vec<(f64, f64)> {
+        points.iter().copied()
+            .filter(|&(px, py)| ((px - x).hypot(py - y) - r).abs() <= TOLERANCE)
+            .collect()
+    };
+    let neighbours = |(x, y, r): (f64, f64, f64)| -> usize {
+        let offset = 4. * TOLERANCE;
+        points.iter()
+            .filter(|&&(px, py)| ((px - x).hypot(py - y) - r).abs() - offset <= TOLERANCE)
+            .filter(|&&(px, py)| ((px - x).hypot(py - y) - r).abs() - offset >= -TOLERANCE)
+            .count() / 2
+    };
+
+    // deterministic xorshift, so registering the same frames gives the same result
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % n as u64) as usize
+    };
+    let mut best = None;
+    let mut best_support = 0;
+    for _ in 0..ITERATIONS {
+        let sample = [points[random(points.len())], points[random(points.len())], points[random(points.len())]];
+        let Some(circle) = circle_through(sample) else {
+            continue;
+        };
+        if circle.2 > max_radius {
+            continue;
+        }
+        let support = points.iter()
+            .filter(|&&(px, py)| ((px - circle.0).hypot(py - circle.1) - circle.2).abs() <= TOLERANCE)
+            .count();
+        if support > best_support {
+            best = Some(circle);
+            best_support = support;
+        }
+    }
+    let mut circle = best?;
+    // refine on the supporting points, which change as the circle moves
+    let mut support = inliers(circle);
+    for _ in 0..3 {
+        circle = circle_least_squares(&support)?;
+        support = inliers(circle);
+    }
+    let (middlex, middley, radius) = circle;
+    if support.len() < MIN_INLIERS
+        || (support.len() as f32) < MIN_COVERAGE * 2. * PI * radius as f32
+        || support.len() < MIN_CONTRAST * neighbours(circle) {
+        return None;
+    }
+    Some(DiskRegistration { middlex: middlex as f32, middley: middley as f32, radius: radius as f32, inliers: support.len() as u32 })
+}
+
+/// Centre and radius of the circle through three points, `None` if they are collinear
+fn circle_through([(x1, y1), (x2, y2), (x3, y3)]: [(f64, f64); 3]) -> Option<(f64, f64, f64)> {
+    let d = 2. * (x1 * (y2 - y3) + x2 * (y3 - y1) + x3 * (y1 - y2));
+    if d.abs() < 1e-9 {
+        return None;
+    }
+    let (s1, s2, s3) = (x1 * x1 + y1 * y1, x2 * x2 + y2 * y2, x3 * x3 + y3 * y3);
+    let x = (s1 * (y2 - y3) + s2 * (y3 - y1) + s3 * (y1 - y2)) / d;
+    let y = (s1 * (x3 - x2) + s2 * (x1 - x3) + s3 * (x2 - x1)) / d;
+    Some((x, y, (x1 - x).hypot(y1 - y)))
+}
+
+/// Algebraic least squares circle fit minimizing the residuals of `x² + y² + ax + by + c = 0`
+fn circle_least_squares(points: &[(f64, f64)]) -> Option<(f64, f64, f64)> {
+    if points.len() < 3 {
+        return None;
+    }
+    // normal equations of the linear system in a, b and c
+    let mut m = [[0.; 3]; 3];
+    let mut v = [0.; 3];
+    for &(x, y) in points {
+        let row = [x, y, 1.];
+        let rhs = -(x * x + y * y);
+        for i in 0..3 {
+            for j in 0..3 {
+                m[i][j] += row[i] * row[j];
+            }
+            v[i] += row[i] * rhs;
+        }
+    }
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let determinant = det(m);
+    if determinant.abs() < 1e-9 {
+        return None;
+    }
+    // Cramer's rule
+    let [a, b, c] = std::array::from_fn(|column| {
+        let mut replaced = m;
+        for (row, value) in replaced.iter_mut().zip(v) {
+            row[column] = value;
+        }
+        det(replaced) / determinant
+    });
+    let (x, y) = (-a / 2., -b / 2.);
+    let radius_squared = x * x + y * y - c;
+    (radius_squared > 0.).then(|| (x, y, radius_squared.sqrt()))
+}
+
+pub fn single_object_detection<P: FloatPixel>(buf: &FloatImage<P>, threshold: f64) -> SodRegistration {
     sod_aba(buf, threshold, 1.0).0
 }
 
-pub fn average_brightness(buf: &Rgb64FImage, threshold: f64) -> AbaRegistration {
+pub fn average_brightness<P: FloatPixel>(buf: &FloatImage<P>, threshold: f64) -> AbaRegistration {
     sod_aba(buf, 1.0, threshold).1
 }
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use plotters::backend::BitMapBackend;
+use plotters::chart::ChartBuilder;
+use plotters::drawing::IntoDrawingArea;
+use plotters::element::Circle;
+use plotters::series::LineSeries;
+use plotters::style::{BLACK, Color, GREEN, RED, WHITE};
+use crate::{CommonArgs, helpers, Reject, rejection};
+use crate::register::Registration;
+use crate::rejection::{RejectionReport, Verdict};
+
+pub fn reject(common: CommonArgs, reject: Reject) {
+    let CommonArgs { colorspace: _, num_files, skip_files, mono: _, max_memory: _, prefetch: _ } = common;
+    let Reject { registration_input, rejection, rejection_policy, outfile, report: report_prefix } = reject;
+
+    let registration = helpers::load_registration(registration_input);
+    let images = helpers::clamp_slice(&registration.images, skip_files, num_files);
+    let (mut images, report) = rejection::reject_with_report(&registration, images.to_owned(), &rejection, rejection_policy);
+    print_table(&report);
+    rejection::print_summary(&report);
+    serde_json::to_writer_pretty(File::create(report_prefix.with_extension("json")).unwrap(), &report).unwrap();
+    plot(&report, &report_prefix.with_extension("png"));
+
+    // the offsets are relative to the reference image, so it must be kept
+    let reference = &registration.images[registration.reference_image];
+    let reference_image = match images.iter().position(|image| image.image == reference.image) {
+        Some(index) => index,
+        None => {
+            println!("reference image {} was rejected, keeping it as reference", reference.image.display());
+            // keep the frames in sequence order for the rules and smoothing of later runs
+            let positions: HashMap<&Path, usize> = registration.images.iter().enumerate()
+                .map(|(i, image)| (image.image.as_path(), i))
+                .collect();
+            let index = images.partition_point(|image| positions[image.image.as_path()] < registration.reference_image);
+            images.insert(index, reference.clone());
+            index
+        }
+    };
+    helpers::save_registration(outfile, &Registration { reference_image, images });
+}
+
+fn print_table(report: &RejectionReport) {
+    print!("{:>6} {:<30}", "frame", "image");
+    for rule in &report.rules {
+        print!(" {rule:>30}");
+    }
+    println!(" verdict");
+    for (i, frame) in report.frames.iter().enumerate() {
+        let name = frame.image.file_name().unwrap_or_default().to_string_lossy();
+        print!("{i:>6} {name:<30}");
+        for verdict in &frame.verdicts {
+            let verdict_str = if verdict.accepted { "ok" } else { "REJECT" };
+            let cell = match verdict {
+                Verdict { value: None, .. } => format!("n/a {verdict_str}"),
+                Verdict { value: Some(value), limit, .. } => format!("{value:.3e} / {limit:.3e} {verdict_str}"),
+            };
+            print!(" {cell:>30}");
+        }
+        println!(" {}", if frame.accepted { "accepted" } else { "rejected" });
+    }
+}
+
+/// Plot the measured values of each rule over time with their limit, rejected frames in red
+fn plot(report: &RejectionReport, path: &Path) {
+    if report.rules.is_empty() {
+        return;
+    }
+    let root = BitMapBackend::new(path, (1920, 360 * report.rules.len() as u32)).into_drawing_area();
+    root.fill(&WHITE).unwrap();
+    for (i, (rule, area)) in report.rules.iter().zip(root.split_evenly((report.rules.len(), 1))).enumerate() {
+        let points: Vec<(f32, f32, bool)> = report.frames.iter().enumerate()
+            .filter_map(|(frame, report)| {
+                let verdict = report.verdicts[i];
+                let value = verdict.value.filter(|value| value.is_finite())?;
+                Some((frame as f32, value, verdict.accepted))
+            }).collect();
+        // the percentile limit is -inf if no frame is kept
+        let limit = report.frames.first()
+            .map(|frame| frame.verdicts[i].limit)
+            .filter(|limit| limit.is_finite());
+        let (min, max) = points.iter().map(|&(_, value, _)| value).chain(limit)
+            .fold(None, |range, value| match range {
+                None => Some((value, value)),
+                Some((min, max)) => Some((value.min(min), value.max(max))),
+            })
+            .unwrap_or((0., 1.));
+        let margin = ((max - min) * 0.05).max(f32::EPSILON);
+
+        let mut chart = ChartBuilder::on(&area)
+            .caption(rule, ("sans-serif", 20))
+            .x_label_area_size(30)
+            .y_label_area_size(60)
+            .build_cartesian_2d(0f32..report.frames.len() as f32, min - margin..max + margin).unwrap();
+        chart.configure_mesh().disable_x_mesh().disable_y_mesh().draw().unwrap();
+        if let Some(limit) = limit {
+            chart.draw_series(LineSeries::new([(0., limit), (report.frames.len() as f32, limit)], BLACK)).unwrap();
+        }
+        chart.draw_series(points.iter().map(|&(frame, value, accepted)| {
+            Circle::new((frame, value), 2, if accepted { GREEN.filled() } else { RED.filled() })
+        })).unwrap();
+    }
+    root.present().unwrap();
+}
@@ -1,82 +1,219 @@
-use crate::register::{ImageRegistration, Registration};
-use crate::{Colorspace, helpers, Rejection};
+use std::ops::Range;
+use std::path::PathBuf;
+use serde::Serialize;
+use crate::{Rejection, RejectionPolicy, source, Threshold};
+use crate::register::{self, ImageRegistration, Registration};
 
-pub fn reject(registration: &Registration, mut images: Vec<ImageRegistration>, rejections: &[Rejection]) -> Vec<ImageRegistration> {
-    let reference = &registration.images[registration.reference_image];
-    let reference_image = helpers::load_image(&reference.image, Colorspace::Srgb);
-    let width = reference_image.width();
-    let height = reference_image.height();
-    for rejection in rejections {
-        images = match rejection {
-            &Rejection::AverageSod(threshold) => average(&images, threshold, width, height, |r| { let (a,b) = r.sod.middle(); (a as f32, b as f32) }),
-            &Rejection::AverageAba(threshold) => average(&images, threshold, width, height, |r| (r.aba.middlex, r.aba.middley)),
-            &Rejection::RegressionAkaze(threshold) => regression(&images, threshold, width, height, |r| r.akaze.unwrap().offset()),
-            &Rejection::RegressionSod(threshold) => regression(&images, threshold, width, height, |r| r.sod.offset(&reference.sod)),
-            &Rejection::RegressionAba(threshold) => regression(&images, threshold, width, height, |r| r.aba.offset(&reference.aba)),
-            &Rejection::WidthHeight(threshold) => width_height(&images, threshold, &reference),
-        }
-    }
+/// Measured value of a frame for a rejection rule compared against the rule's limit
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Verdict {
+    /// `None` if the value couldn't be measured
+    pub value: Option<f32>,
+    /// measured statistic of rules comparing its deviation from the median as `value`
+    pub measured: Option<f32>,
+    pub limit: f32,
+    pub accepted: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameReport {
+    pub image: PathBuf,
+    pub accepted: bool,
+    /// verdict of each rule
+    pub verdicts: Vec<Verdict>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectionReport {
+    pub rules: Vec<String>,
+    pub policy: String,
+    pub frames: Vec<FrameReport>,
+}
+
+pub fn reject(registration: &Registration, images: Vec<ImageRegistration>, rejections: &[Rejection], policy: RejectionPolicy) -> Vec<ImageRegistration> {
+    let (images, report) = reject_with_report(registration, images, rejections, policy);
+    print_summary(&report);
     images
 }
 
-fn average(images: &[ImageRegistration], threshold: f32, width: u32, height: u32, middle_fn: impl Fn(&ImageRegistration) -> (f32, f32)) -> Vec<ImageRegistration> {
-    images.windows(3).filter_map(|window| {
-        let (a,b,c) = match window {
-            [a, b, c] => (a,b,c),
-            _ => unreachable!(),
+/// Evaluate every rule on every frame of the sequence and combine their verdicts by the policy
+pub fn reject_with_report(registration: &Registration, images: Vec<ImageRegistration>, rejections: &[Rejection], policy: RejectionPolicy) -> (Vec<ImageRegistration>, RejectionReport) {
+    let reference = &registration.images[registration.reference_image];
+    let (width, height) = source::dimensions(&reference.image);
+    let verdicts: Vec<Vec<Verdict>> = rejections.iter().map(|rejection| match rejection {
+        &Rejection::AverageSod(threshold) => average(&images, threshold, width, height, |r| { let (a,b) = r.sod.middle(); (a as f32, b as f32) }),
+        &Rejection::AverageAba(threshold) => average(&images, threshold, width, height, |r| (r.aba.middlex, r.aba.middley)),
+        &Rejection::RegressionAkaze(threshold) => regression(&images, threshold, width, height, |r| Some(r.akaze?.offset())),
+        &Rejection::RegressionSod(threshold) => regression(&images, threshold, width, height, |r| Some(r.sod.offset(&reference.sod))),
+        &Rejection::RegressionAba(threshold) => regression(&images, threshold, width, height, |r| Some(r.aba.offset(&reference.aba))),
+        &Rejection::WidthHeight(threshold) => width_height(&images, threshold, reference),
+        &Rejection::Brightness(threshold) => statistic(&images, threshold, Bad::Both, |r| Some(r.statistics?.brightness)),
+        &Rejection::Background(threshold) => statistic(&images, threshold, Bad::High, |r| Some(r.statistics?.background)),
+        &Rejection::Stars(threshold) => statistic(&images, threshold, Bad::Low, |r| Some(r.statistics?.stars as f32)),
+        &Rejection::Fwhm(threshold) => statistic(&images, threshold, Bad::High, |r| r.statistics?.fwhm),
+        &Rejection::AkazeInliers(threshold) => statistic(&images, threshold, Bad::Low, |r| Some(r.akaze_confidence?.inliers as f32)),
+        &Rejection::Sharpness(threshold) => statistic(&images, threshold, Bad::Low, |r| Some(r.statistics?.sharpness)),
+    }).collect();
+
+    let frames: Vec<_> = images.iter().enumerate().map(|(i, image)| {
+        let verdicts: Vec<_> = verdicts.iter().map(|verdicts| verdicts[i]).collect();
+        let rejected = verdicts.iter().filter(|verdict| !verdict.accepted).count();
+        let accepted = match policy {
+            RejectionPolicy::Any => rejected == 0,
+            RejectionPolicy::All => verdicts.is_empty() || rejected < verdicts.len(),
+            RejectionPolicy::Majority => rejected * 2 <= verdicts.len(),
         };
-        let (ax, ay) = middle_fn(a);
-        let (bx, by) = middle_fn(b);
-        let (cx, cy) = middle_fn(c);
-        let avgx = (ax + bx + cx) / 3.;
-        let avgy = (ay + by + cy) / 3.;
-        let dx = (bx - avgx).abs();
-        let dy = (by - avgy).abs();
+        FrameReport { image: image.image.clone(), accepted, verdicts }
+    }).collect();
+    let images = images.into_iter()
+        .zip(&frames)
+        .filter(|(_, frame)| frame.accepted)
+        .map(|(image, _)| image)
+        .collect();
+    let report = RejectionReport {
+        rules: rejections.iter().map(|rejection| format!("{rejection:?}")).collect(),
+        policy: format!("{policy:?}"),
+        frames,
+    };
+    (images, report)
+}
+
+pub fn print_summary(report: &RejectionReport) {
+    for (i, rule) in report.rules.iter().enumerate() {
+        let rejected = report.frames.iter()
+            .filter(|frame| !frame.verdicts[i].accepted)
+            .count();
+        println!("{rule} rejected {rejected} frames");
+    }
+    let accepted = report.frames.iter().filter(|frame| frame.accepted).count();
+    println!("accepted {accepted} of {} frames, rejecting frames failing {} of the rules", report.frames.len(), report.policy.to_lowercase());
+}
+
+/// Indices of the window of `size` frames centered at `i`, shrinking at the edges of the sequence
+fn window(i: usize, size: usize, len: usize) -> Range<usize> {
+    let half = size / 2;
+    i.saturating_sub(half)..(i + half + 1).min(len)
+}
+
+fn average(images: &[ImageRegistration], threshold: f32, width: u32, height: u32, middle_fn: impl Fn(&ImageRegistration) -> (f32, f32)) -> Vec<Verdict> {
+    (0..images.len()).map(|i| {
+        let window = window(i, 3, images.len());
+        let len = window.len() as f32;
+        let (sumx, sumy) = images[window].iter()
+            .map(&middle_fn)
+            .fold((0., 0.), |(sumx, sumy), (x, y)| (sumx + x, sumy + y));
+        let (x, y) = middle_fn(&images[i]);
+        let dx = (x - sumx / len).abs();
+        let dy = (y - sumy / len).abs();
         let distance = (dx.powi(2) + dy.powi(2)).sqrt();
         let wh = ((width*width + height*height) as f32).sqrt();
-        if distance / wh >= threshold {
-            None
-        } else {
-            Some(b.clone())
-        }
+        let value = distance / wh;
+        Verdict { value: Some(value), measured: None, limit: threshold, accepted: value < threshold }
     }).collect()
 }
 
-fn regression(images: &[ImageRegistration], threshold: f32, width: u32, height: u32, offset_fn: impl Fn(&ImageRegistration) -> (i32, i32)) -> Vec<ImageRegistration> {
-    images.windows(9)
-        .filter_map(|window| {
-            let points: Vec<_> = window.iter()
-                .map(|p| { let (a,b) = offset_fn(p); (a as f32, b as f32) })
-                .collect();
-            let p = points[4];
-            let (m1, n1): (f32, f32) = linreg::linear_regression_of(&points).unwrap();
+fn regression(images: &[ImageRegistration], threshold: f32, width: u32, height: u32, offset_fn: impl Fn(&ImageRegistration) -> Option<(i32, i32)>) -> Vec<Verdict> {
+    (0..images.len()).map(|i| {
+        // frames without this registration can't be judged
+        let Some(p) = offset_fn(&images[i]).map(|(a, b)| (a as f32, b as f32)) else {
+            return Verdict { value: None, measured: None, limit: threshold, accepted: true };
+        };
+        let window = window(i, 9, images.len());
+        let points: Vec<_> = images[window].iter()
+            .filter_map(|p| { let (a,b) = offset_fn(p)?; Some((a as f32, b as f32)) })
+            .collect();
+        // too few or only vertically distributed points
+        let Some((m1, n1)): Option<(f32, f32)> = linreg::linear_regression_of(&points).ok() else {
+            return Verdict { value: None, measured: None, limit: threshold, accepted: true };
+        };
 
-            // calculate intersecting line
-            // perpendicular => m2 = -1 / m1
-            // y = m1 * x + n1
-            // y = m2 * x + n2
-            // => m1 * x + n1 = m2 * x + n2
-            // => n1 - n2 = x * (m2 - m1)
-            // => x = (n1 / n2) / (m2 - m1)
-            let m2 = -1. / m1;
-            let n2 = p.1 - m2 * p.0;
-            let x1 = (n1 - n2) / (m2 - m1);
-            let y1 = m1 * x1 + n1;
-            let dx = (x1 - p.0).abs();
-            let dy = (y1 - p.1).abs();
-            let percentage = (dx + dy) / (width + height) as f32;
-            if percentage >= threshold {
-                None
-            } else {
-                Some(window[4].clone())
-            }
-        }).collect()
+        // project the point onto the line y = m1 * x + n1 along its normal (-m1, 1),
+        // which for a horizontal line is the vertical distance
+        // => p + t * (-m1, 1) on the line: p.1 + t = m1 * (p.0 - t * m1) + n1
+        // => t = (m1 * p.0 + n1 - p.1) / (1 + m1²)
+        let t = (m1 * p.0 + n1 - p.1) / (1. + m1 * m1);
+        let dx = (t * m1).abs();
+        let dy = t.abs();
+        let percentage = (dx + dy) / (width + height) as f32;
+        Verdict { value: Some(percentage), measured: None, limit: threshold, accepted: percentage < threshold }
+    }).collect()
 }
 
-fn width_height(images: &[ImageRegistration], threshold: f32, reference: &ImageRegistration) -> Vec<ImageRegistration> {
-    images.into_iter().cloned().filter(|i| {
+/// Relative change of the size of the object, using the radius of the disk fit where the frame and the
+/// reference have one, as the detection box also changes with the phase and the seeing
+fn width_height(images: &[ImageRegistration], threshold: f32, reference: &ImageRegistration) -> Vec<Verdict> {
+    images.iter().map(|i| {
+        if let Some((disk, reference)) = i.disk.zip(reference.disk) {
+            let value = (disk.radius / reference.radius - 1.).abs();
+            return Verdict { value: Some(value), measured: None, limit: threshold, accepted: value < threshold };
+        }
         let dwidth = (i.sod.width() as f32 / reference.sod.width() as f32 - 1.).abs();
         let dheight = (i.sod.height() as f32 / reference.sod.height() as f32 - 1.).abs();
-        dwidth >= threshold || dheight >= threshold
+        let value = dwidth.max(dheight);
+        Verdict { value: Some(value), measured: None, limit: threshold, accepted: value < threshold }
+    }).collect()
+}
+
+/// Which deviation from the median is bad
+#[derive(Debug, Copy, Clone)]
+enum Bad {
+    Low,
+    High,
+    Both,
+}
+
+/// Reject frames whose value deviates from the median in the bad direction by more than the threshold.
+///
+/// Frames without the value are kept.
+fn statistic(images: &[ImageRegistration], threshold: Threshold, bad: Bad, value_fn: impl Fn(&ImageRegistration) -> Option<f32>) -> Vec<Verdict> {
+    if images.is_empty() {
+        return Vec::new();
+    }
+    let values: Vec<_> = images.iter().map(&value_fn).collect();
+    let median = match register::median(values.iter().flatten().copied().collect()) {
+        Some(median) => median,
+        None => {
+            println!("no frame statistics available, re-run registration to reject by them");
+            return vec![Verdict { value: None, measured: None, limit: 0., accepted: true }; images.len()];
+        }
+    };
+    let badness = |value: f32| match bad {
+        Bad::Low => median - value,
+        Bad::High => value - median,
+        Bad::Both => (value - median).abs(),
+    };
+    let limit = match threshold {
+        Threshold::Sigma(sigma) => {
+            let deviations: Vec<f32> = values.iter().flatten().map(|v| (v - median).abs()).collect();
+            let mad = register::median(deviations.clone()).unwrap();
+            if mad > 0. {
+                sigma * 1.4826 * mad
+            } else {
+                // most frames have the same value, e.g. quantized statistics like star counts, fall back to
+                // the mean absolute deviation but at least the smallest deviation, i.e. the quantization step
+                let mean = deviations.iter().sum::<f32>() / deviations.len() as f32;
+                let step = deviations.iter().copied().filter(|&d| d > 0.).fold(f32::INFINITY, f32::min);
+                let scale = (1.2533 * mean).max(if step.is_finite() { step } else { 0. });
+                println!("median absolute deviation is 0, using a standard deviation of {scale} instead");
+                sigma * scale
+            }
+        }
+        Threshold::Percentile(percentile) => {
+            let mut badnesses: Vec<f32> = values.iter().flatten().map(|&v| badness(v)).collect();
+            badnesses.sort_by(f32::total_cmp);
+            let keep = ((1. - percentile / 100.) * badnesses.len() as f32).round() as usize;
+            match keep.checked_sub(1) {
+                Some(index) => badnesses[index.min(badnesses.len() - 1)],
+                None => f32::NEG_INFINITY,
+            }
+        }
+    };
+    values.into_iter().map(|measured| {
+        let value = measured.map(badness);
+        let accepted = match value {
+            Some(value) => value <= limit,
+            None => true,
+        };
+        Verdict { value, measured, limit, accepted }
     }).collect()
 }
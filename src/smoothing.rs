@@ -0,0 +1,110 @@
+use crate::register::{self, AkazeRegistration, Registration};
+
+/// Number of robustness iterations of the LOESS fit
+const ROBUSTNESS_ITERATIONS: usize = 2;
+
+/// Smooth the fused offsets of consecutive frames into a drift trajectory.
+///
+/// Frames whose fused offset deviates more than `snap` sigmas from the trajectory are considered
+/// noisy and snap to it. With `interpolate_akaze`, rejected AKAZE registrations are replaced by
+/// the smoothed AKAZE trajectory.
+pub fn smooth(reg: &mut Registration, window: usize, snap: f32, interpolate_akaze: bool) {
+    let fused: Vec<_> = reg.images.iter().map(|image| image.fused).collect();
+    let trajectory = loess_offsets(&fused, window);
+    let distances: Vec<f32> = fused.iter().zip(&trajectory)
+        .filter_map(|(&fused, &smoothed)| Some(distance(fused?, smoothed?)))
+        .collect();
+    // at least half a pixel due to rounding
+    let sigma = (register::median(distances).unwrap_or_default() * 1.4826).max(0.5);
+
+    let mut snapped = 0;
+    for ((image, fused), smoothed) in reg.images.iter_mut().zip(fused).zip(trajectory) {
+        image.smoothed = match (fused, smoothed) {
+            (Some(fused), Some(smoothed)) if distance(fused, smoothed) > snap * sigma => {
+                snapped += 1;
+                Some(smoothed)
+            }
+            (fused, smoothed) => fused.or(smoothed),
+        };
+    }
+    println!("trajectory sigma {sigma:.2}, snapped {snapped} frames to the trajectory");
+
+    if interpolate_akaze {
+        let akaze: Vec<_> = reg.images.iter()
+            .map(|image| match image.akaze {
+                Some(AkazeRegistration::Offset(dx, dy)) => Some((dx, dy)),
+                _ => None,
+            }).collect();
+        let trajectory = loess_offsets(&akaze, window);
+        let mut interpolated = 0;
+        for (image, smoothed) in reg.images.iter_mut().zip(trajectory) {
+            if let (Some(AkazeRegistration::Rejected), Some((dx, dy))) = (image.akaze, smoothed) {
+                image.akaze = Some(AkazeRegistration::Interpolated(dx, dy));
+                interpolated += 1;
+            }
+        }
+        println!("interpolated {interpolated} rejected akaze registrations");
+    }
+}
+
+fn distance((x1, y1): (f32, f32), (x2, y2): (f32, f32)) -> f32 {
+    ((x1 - x2).powi(2) + (y1 - y2).powi(2)).sqrt()
+}
+
+fn loess_offsets(offsets: &[Option<(f32, f32)>], window: usize) -> Vec<Option<(f32, f32)>> {
+    let xs = loess(&offsets.iter().map(|o| o.map(|(x, _)| x)).collect::<Vec<_>>(), window);
+    let ys = loess(&offsets.iter().map(|o| o.map(|(_, y)| y)).collect::<Vec<_>>(), window);
+    xs.into_iter().zip(ys).map(|(x, y)| Some((x?, y?))).collect()
+}
+
+/// Robust locally weighted linear regression (LOESS) of a time series with missing values.
+///
+/// Every value is fitted from the known values within the window around it with tricube weights.
+/// Outliers are down-weighted in further iterations with bisquare weights of their residuals.
+/// Missing values are interpolated if there are known values within the window.
+fn loess(values: &[Option<f32>], window: usize) -> Vec<Option<f32>> {
+    let half = (window / 2).max(1) as i64;
+    let mut robustness = vec![1f32; values.len()];
+    let mut fitted = vec![None; values.len()];
+    for iteration in 0..=ROBUSTNESS_ITERATIONS {
+        for (i, fit) in fitted.iter_mut().enumerate() {
+            let i = i as i64;
+            let points: Vec<(f32, f32, f32)> = (i - half..=i + half)
+                .filter(|&j| j >= 0 && (j as usize) < values.len())
+                .filter_map(|j| {
+                    let value = values[j as usize]?;
+                    let tricube = (1. - ((j - i).abs() as f32 / (half + 1) as f32).powi(3)).powi(3);
+                    Some(((j - i) as f32, value, tricube * robustness[j as usize]))
+                }).collect();
+            *fit = linear_fit_at_zero(&points);
+        }
+        if iteration == ROBUSTNESS_ITERATIONS {
+            break;
+        }
+        let residuals: Vec<Option<f32>> = values.iter().zip(&fitted)
+            .map(|(&value, &fit)| Some((value? - fit?).abs()))
+            .collect();
+        // offsets are in pixels, residuals below half a pixel are within the rounding error
+        let scale = 6. * register::median(residuals.iter().flatten().copied().collect()).unwrap_or_default().max(0.5);
+        for (robustness, residual) in robustness.iter_mut().zip(residuals) {
+            *robustness = residual.map_or(1., |r| (1. - (r / scale).min(1.).powi(2)).powi(2));
+        }
+    }
+    fitted
+}
+
+/// Weighted least squares line through `(x, y, weight)` points, evaluated at `x = 0`
+fn linear_fit_at_zero(points: &[(f32, f32, f32)]) -> Option<f32> {
+    let weights: f32 = points.iter().map(|&(_, _, w)| w).sum();
+    if weights <= 0. {
+        return None;
+    }
+    let mean_x = points.iter().map(|&(x, _, w)| w * x).sum::<f32>() / weights;
+    let mean_y = points.iter().map(|&(_, y, w)| w * y).sum::<f32>() / weights;
+    let covariance: f32 = points.iter().map(|&(x, y, w)| w * (x - mean_x) * (y - mean_y)).sum();
+    let variance: f32 = points.iter().map(|&(x, _, w)| w * (x - mean_x).powi(2)).sum();
+    if variance <= f32::EPSILON {
+        return Some(mean_y);
+    }
+    Some(mean_y - covariance / variance * mean_x)
+}
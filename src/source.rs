@@ -0,0 +1,514 @@
+//! Frame sources: image files and frames of MP4 (H.264), AVI (uncompressed or MJPEG) and SER videos.
+//!
+//! Frames of videos are addressed as `<video>#<index>`, so they can be stored in the registration
+//! like image files and loaded again by later passes.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageFormat, Luma, Rgb, RgbImage};
+use image::io::Reader;
+use openh264::decoder::Decoder;
+
+/// Number of digits of the frame index in frame paths, keeps them sorted by name
+const INDEX_DIGITS: usize = 6;
+
+thread_local! {
+    /// Last opened video of this thread, frames are usually loaded in order from a single thread
+    static VIDEO: RefCell<Option<(PathBuf, Video)>> = const { RefCell::new(None) };
+}
+
+/// SER timestamp of the Unix epoch
+const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+
+pub fn is_video(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    matches!(extension.as_str(), "mp4" | "m4v" | "mov" | "avi" | "ser")
+}
+
+/// Paths of all frames of the video
+pub fn video_frames(video: &Path) -> Vec<PathBuf> {
+    let frames = with_video(video, |video| video.frame_count());
+    (0..frames)
+        .map(|index| {
+            let mut path = video.as_os_str().to_owned();
+            path.push(format!("#{index:0INDEX_DIGITS$}"));
+            PathBuf::from(path)
+        }).collect()
+}
+
+/// Video and frame index of a frame path, `None` for image files
+fn split_frame_path(path: &Path) -> Option<(&Path, usize)> {
+    let path_str = path.to_str()?;
+    let (video, index) = path_str.rsplit_once('#')?;
+    let video = Path::new(video);
+    if !is_video(video) {
+        return None;
+    }
+    Some((video, index.parse().ok()?))
+}
+
+pub fn load(path: &Path) -> DynamicImage {
+    match split_frame_path(path) {
+        Some((video, index)) => with_video(video, |video| video.frame(index))
+            .unwrap_or_else(|e| panic!("error loading {}: {e}", path.display())),
+        None => Reader::open(path).unwrap().decode().unwrap(),
+    }
+}
+
+pub fn dimensions(path: &Path) -> (u32, u32) {
+    match split_frame_path(path) {
+        Some((video, _)) => with_video(video, |video| video.dimensions()),
+        None => image::image_dimensions(path).unwrap(),
+    }
+}
+
+/// Capture time of the frame in seconds since the Unix epoch.
+///
+/// Frames of SER videos use the timestamps of the trailer, other frames the modification time of their file.
+pub fn timestamp(path: &Path) -> Option<f64> {
+    let file = match split_frame_path(path) {
+        Some((video, index)) => match with_video(video, |video| video.timestamp(index)) {
+            Some(timestamp) => return Some(timestamp),
+            None => video,
+        },
+        None => path,
+    };
+    let modified = std::fs::metadata(file).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs_f64())
+}
+
+fn with_video<R>(path: &Path, f: impl FnOnce(&mut Video) -> R) -> R {
+    VIDEO.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        if !matches!(&*cached, Some((cached_path, _)) if cached_path == path) {
+            *cached = Some((path.to_owned(), Video::open(path)));
+        }
+        f(&mut cached.as_mut().unwrap().1)
+    })
+}
+
+enum Video {
+    Mp4(Mp4),
+    Avi(Avi),
+    Ser(Ser),
+}
+
+impl Video {
+    fn open(path: &Path) -> Video {
+        let mut file = BufReader::new(File::open(path).unwrap());
+        let mut magic = [0; 12];
+        file.read_exact(&mut magic).unwrap();
+        file.rewind().unwrap();
+        if &magic[..4] == b"RIFF" && &magic[8..] == b"AVI " {
+            Video::Avi(Avi::open(file, path))
+        } else if magic.starts_with(b"LUCAM-RECOR") {
+            Video::Ser(Ser::open(file, path))
+        } else {
+            Video::Mp4(Mp4::open(file, path))
+        }
+    }
+
+    fn frame_count(&self) -> usize {
+        match self {
+            Video::Mp4(mp4) => mp4.samples.len(),
+            Video::Avi(avi) => avi.frames.len(),
+            Video::Ser(ser) => ser.frame_count,
+        }
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Video::Mp4(mp4) => (mp4.width, mp4.height),
+            Video::Avi(avi) => (avi.width, avi.height.unsigned_abs()),
+            Video::Ser(ser) => (ser.width, ser.height),
+        }
+    }
+
+    fn frame(&mut self, index: usize) -> Result<DynamicImage, String> {
+        match self {
+            Video::Mp4(mp4) => mp4.frame(index),
+            Video::Avi(avi) => Ok(avi.frame(index)),
+            Video::Ser(ser) => Ok(ser.frame(index)),
+        }
+    }
+
+    /// Capture time of the frame in seconds since the Unix epoch if the video stores it
+    fn timestamp(&self, index: usize) -> Option<f64> {
+        match self {
+            Video::Mp4(_) | Video::Avi(_) => None,
+            // ticks of 100 ns since 0001-01-01, capture software not setting them writes 0
+            Video::Ser(ser) => ser.timestamps.get(index)
+                .filter(|&&ticks| ticks > UNIX_EPOCH_TICKS)
+                .map(|&ticks| (ticks - UNIX_EPOCH_TICKS) as f64 / 1e7),
+        }
+    }
+}
+
+fn read_bytes(file: &mut BufReader<File>, offset: u64, len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    buf
+}
+
+fn be_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+fn be_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+fn le_i64(data: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// H.264 video track of an MP4 file.
+///
+/// Frames can only be decoded starting from a sync sample, so loading frames in order is much
+/// faster than random access. Only profiles supported by openh264 can be decoded, which excludes
+/// B-frames.
+struct Mp4 {
+    file: BufReader<File>,
+    width: u32,
+    height: u32,
+    /// file offset and size of each sample
+    samples: Vec<(u64, usize)>,
+    /// indices of the samples that can be decoded without previous samples
+    sync_samples: Vec<usize>,
+    /// SPS and PPS NAL units in Annex B format, prepended to sync samples
+    parameter_sets: Vec<u8>,
+    /// size of the length prefix of the NAL units in the samples
+    nal_length_size: usize,
+    /// decoder and the index of the frame it decoded last
+    decoder: Option<(Decoder, usize)>,
+}
+
+/// Children of an MP4 box as `(type, content)`
+fn mp4_boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let (header, size) = match be_u32(data, offset) {
+            0 => (8, data.len() - offset),
+            1 => (16, be_u64(data, offset + 8) as usize),
+            size => (8, size as usize),
+        };
+        let end = (offset + size).min(data.len());
+        boxes.push((&data[offset + 4..offset + 8], &data[offset + header..end]));
+        offset = end;
+    }
+    boxes
+}
+
+fn mp4_box<'a>(data: &'a [u8], typ: &[u8]) -> Option<&'a [u8]> {
+    mp4_boxes(data).into_iter().find(|&(t, _)| t == typ).map(|(_, content)| content)
+}
+
+impl Mp4 {
+    fn open(mut file: BufReader<File>, path: &Path) -> Mp4 {
+        // only read the movie box instead of the whole file
+        let len = file.get_ref().metadata().unwrap().len();
+        let mut offset = 0;
+        let moov = loop {
+            assert!(offset + 8 <= len, "no moov box in {}", path.display());
+            let header = read_bytes(&mut file, offset, 16.min(len - offset) as usize);
+            let (header_len, size) = match be_u32(&header, 0) {
+                0 => (8, len - offset),
+                1 => (16, be_u64(&header, 8)),
+                size => (8, size as u64),
+            };
+            if &header[4..8] == b"moov" {
+                break read_bytes(&mut file, offset + header_len, (size - header_len) as usize);
+            }
+            offset += size;
+        };
+
+        let stbl = mp4_boxes(&moov).into_iter()
+            .filter(|&(typ, _)| typ == b"trak")
+            .filter_map(|(_, trak)| mp4_box(trak, b"mdia"))
+            // handler type after version, flags and pre-defined field
+            .find(|mdia| mp4_box(mdia, b"hdlr").is_some_and(|hdlr| hdlr.get(8..12) == Some(b"vide".as_slice())))
+            .and_then(|mdia| mp4_box(mp4_box(mdia, b"minf")?, b"stbl"))
+            .unwrap_or_else(|| panic!("no video track in {}", path.display()));
+
+        // sample description: visual sample entry with the avcC box after its 78 byte header
+        let stsd = mp4_box(stbl, b"stsd").unwrap();
+        let (entry_type, entry) = mp4_boxes(&stsd[8..])[0];
+        assert!(entry_type == b"avc1" || entry_type == b"avc3", "{} is not H.264 but {}", path.display(), String::from_utf8_lossy(entry_type));
+        let width = be_u16(entry, 24) as u32;
+        let height = be_u16(entry, 26) as u32;
+        let avcc = mp4_box(&entry[78..], b"avcC").unwrap();
+        let nal_length_size = (avcc[4] & 0b11) as usize + 1;
+        let mut parameter_sets = Vec::new();
+        let mut offset = 5;
+        for _ in 0..2 {
+            // number of SPS, then number of PPS
+            let count = if offset == 5 { avcc[offset] & 0x1f } else { avcc[offset] };
+            offset += 1;
+            for _ in 0..count {
+                let len = be_u16(avcc, offset) as usize;
+                parameter_sets.extend_from_slice(&[0, 0, 0, 1]);
+                parameter_sets.extend_from_slice(&avcc[offset + 2..offset + 2 + len]);
+                offset += 2 + len;
+            }
+        }
+
+        let stsz = mp4_box(stbl, b"stsz").unwrap();
+        let sample_count = be_u32(stsz, 8) as usize;
+        let sizes: Vec<usize> = match be_u32(stsz, 4) {
+            0 => (0..sample_count).map(|i| be_u32(stsz, 12 + 4 * i) as usize).collect(),
+            size => vec![size as usize; sample_count],
+        };
+        let chunk_offsets: Vec<u64> = match (mp4_box(stbl, b"stco"), mp4_box(stbl, b"co64")) {
+            (Some(stco), _) => (0..be_u32(stco, 4) as usize).map(|i| be_u32(stco, 8 + 4 * i) as u64).collect(),
+            (None, Some(co64)) => (0..be_u32(co64, 4) as usize).map(|i| be_u64(co64, 8 + 8 * i)).collect(),
+            (None, None) => panic!("no chunk offsets in {}", path.display()),
+        };
+        // runs of chunks with the same number of samples as (first chunk, samples per chunk)
+        let stsc = mp4_box(stbl, b"stsc").unwrap();
+        let runs: Vec<(usize, usize)> = (0..be_u32(stsc, 4) as usize)
+            .map(|i| (be_u32(stsc, 8 + 12 * i) as usize - 1, be_u32(stsc, 12 + 12 * i) as usize))
+            .collect();
+        let mut samples = Vec::with_capacity(sample_count);
+        for (chunk, &chunk_offset) in chunk_offsets.iter().enumerate() {
+            let samples_per_chunk = runs.iter().rev()
+                .find(|&&(first, _)| first <= chunk)
+                .map_or(0, |&(_, samples)| samples);
+            let mut offset = chunk_offset;
+            for _ in 0..samples_per_chunk {
+                let Some(&size) = sizes.get(samples.len()) else { break };
+                samples.push((offset, size));
+                offset += size as u64;
+            }
+        }
+        let sync_samples = match mp4_box(stbl, b"stss") {
+            Some(stss) => (0..be_u32(stss, 4) as usize).map(|i| be_u32(stss, 8 + 4 * i) as usize - 1).collect(),
+            None => (0..samples.len()).collect(),
+        };
+
+        Mp4 { file, width, height, samples, sync_samples, parameter_sets, nal_length_size, decoder: None }
+    }
+
+    /// Sample converted from length-prefixed NAL units to Annex B
+    fn annex_b(&mut self, index: usize) -> Vec<u8> {
+        let (offset, size) = self.samples[index];
+        let sample = read_bytes(&mut self.file, offset, size);
+        let mut annex_b = Vec::with_capacity(size + self.parameter_sets.len());
+        if self.sync_samples.binary_search(&index).is_ok() {
+            annex_b.extend_from_slice(&self.parameter_sets);
+        }
+        let mut offset = 0;
+        while offset + self.nal_length_size <= sample.len() {
+            let len = sample[offset..offset + self.nal_length_size].iter()
+                .fold(0, |len, &byte| len << 8 | byte as usize);
+            offset += self.nal_length_size;
+            annex_b.extend_from_slice(&[0, 0, 0, 1]);
+            annex_b.extend_from_slice(&sample[offset..(offset + len).min(sample.len())]);
+            offset += len;
+        }
+        annex_b
+    }
+
+    fn frame(&mut self, index: usize) -> Result<DynamicImage, String> {
+        if index >= self.samples.len() {
+            return Err(format!("frame {index} is beyond the {} frames of the video", self.samples.len()));
+        }
+        // continue decoding if this is the next frame, otherwise start at the previous sync sample
+        let start = match &self.decoder {
+            Some((_, last)) if *last + 1 == index => index,
+            _ => {
+                let sync = self.sync_samples.partition_point(|&sync| sync <= index);
+                let start = sync.checked_sub(1)
+                    .map(|sync| self.sync_samples[sync])
+                    .ok_or_else(|| format!("no sync sample to start decoding frame {index} from"))?;
+                self.decoder = Some((Decoder::new().map_err(|e| format!("{e}"))?, 0));
+                start
+            }
+        };
+        let mut image = None;
+        for i in start..=index {
+            let annex_b = self.annex_b(i);
+            let (decoder, last) = self.decoder.as_mut().unwrap();
+            *last = i;
+            let yuv = decoder.decode(&annex_b)
+                .map_err(|e| format!("error decoding frame {i}: {e}"))?;
+            if i == index {
+                let (width, height) = yuv.dimension_rgb();
+                let mut rgb = vec![0; width * height * 3];
+                yuv.write_rgb8(&mut rgb).map_err(|e| format!("{e}"))?;
+                image = RgbImage::from_raw(width as u32, height as u32, rgb);
+            }
+        }
+        image.map(DynamicImage::ImageRgb8).ok_or_else(|| format!("frame {index} decoded to no image"))
+    }
+}
+
+/// Video stream of an AVI file, either uncompressed 8 bit grey, 24 or 32 bit BGR, or MJPEG
+struct Avi {
+    file: BufReader<File>,
+    width: u32,
+    /// positive for bottom-up, negative for top-down frames
+    height: i32,
+    bit_count: u16,
+    compression: [u8; 4],
+    /// file offset and size of each frame
+    frames: Vec<(u64, usize)>,
+}
+
+impl Avi {
+    fn open(mut file: BufReader<File>, path: &Path) -> Avi {
+        let len = file.get_ref().metadata().unwrap().len();
+        let mut format = None;
+        let mut video_stream = 0;
+        let mut frames = Vec::new();
+        // walk the RIFF chunks, descending into lists; OpenDML files have further `AVIX` RIFF chunks
+        let mut stream = 0;
+        let mut offset = 0;
+        while offset + 8 <= len {
+            let header = read_bytes(&mut file, offset, 12.min(len - offset) as usize);
+            let id = &header[..4];
+            let size = le_u32(&header, 4) as u64;
+            if (id == b"RIFF" || id == b"LIST") && header.len() == 12 {
+                if &header[8..12] == b"strl" {
+                    stream += 1;
+                }
+                offset += 12;
+                continue;
+            }
+            match id {
+                b"strh" if &read_bytes(&mut file, offset + 8, 4) == b"vids" => video_stream = stream - 1,
+                b"strf" if stream - 1 == video_stream && format.is_none() => format = Some(read_bytes(&mut file, offset + 8, 20)),
+                [a, b, kind @ ..] if matches!(kind, b"db" | b"dc") && size > 0 => {
+                    let index = std::str::from_utf8(&[*a, *b]).ok().and_then(|index| index.parse::<usize>().ok());
+                    if index == Some(video_stream) {
+                        frames.push((offset + 8, size as usize));
+                    }
+                }
+                _ => (),
+            }
+            // chunks are padded to an even size
+            offset += 8 + size + (size & 1);
+        }
+
+        let format = format.unwrap_or_else(|| panic!("no video stream in {}", path.display()));
+        Avi {
+            file,
+            width: le_u32(&format, 4),
+            height: le_u32(&format, 8) as i32,
+            bit_count: le_u16(&format, 14),
+            compression: format[16..20].try_into().unwrap(),
+            frames,
+        }
+    }
+
+    fn frame(&mut self, index: usize) -> DynamicImage {
+        let (offset, size) = self.frames[index];
+        let data = read_bytes(&mut self.file, offset, size);
+        let width = self.width as usize;
+        let height = self.height.unsigned_abs() as usize;
+        match (&self.compression, self.bit_count) {
+            (b"MJPG", _) => image::load_from_memory_with_format(&data, ImageFormat::Jpeg).unwrap(),
+            (b"Y800" | b"GREY" | b"Y8  ", _) => DynamicImage::ImageLuma8(GrayImage::from_raw(width as u32, height as u32, data).unwrap()),
+            ([0, 0, 0, 0] | b"DIB ", 8 | 24 | 32) => {
+                // rows are padded to multiples of 4 bytes and stored bottom-up for positive heights
+                let bytes_per_pixel = self.bit_count as usize / 8;
+                let stride = (width * bytes_per_pixel).next_multiple_of(4);
+                let row = |y: usize| {
+                    let y = if self.height > 0 { height - 1 - y } else { y };
+                    &data[y * stride..y * stride + width * bytes_per_pixel]
+                };
+                if bytes_per_pixel == 1 {
+                    // 8 bit frames of astronomy cameras have a grey palette
+                    let grey = (0..height).flat_map(|y| row(y).to_vec()).collect();
+                    DynamicImage::ImageLuma8(GrayImage::from_raw(width as u32, height as u32, grey).unwrap())
+                } else {
+                    let rgb = (0..height)
+                        .flat_map(|y| row(y).chunks(bytes_per_pixel).flat_map(|bgr| [bgr[2], bgr[1], bgr[0]]).collect::<Vec<_>>())
+                        .collect();
+                    DynamicImage::ImageRgb8(RgbImage::from_raw(width as u32, height as u32, rgb).unwrap())
+                }
+            }
+            (compression, bit_count) => panic!("unsupported AVI format {} with {bit_count} bits", String::from_utf8_lossy(compression)),
+        }
+    }
+}
+
+/// SER video of 8 to 16 bit mono, RGB or BGR frames; raw bayer frames are read as mono
+struct Ser {
+    file: BufReader<File>,
+    width: u32,
+    height: u32,
+    /// `100` for RGB, `101` for BGR, otherwise mono or bayer
+    color_id: u32,
+    bit_depth: u32,
+    frame_count: usize,
+    /// capture time of each frame from the trailer
+    timestamps: Vec<i64>,
+}
+
+impl Ser {
+    const HEADER_SIZE: u64 = 178;
+
+    fn open(mut file: BufReader<File>, path: &Path) -> Ser {
+        let header = read_bytes(&mut file, 0, Ser::HEADER_SIZE as usize);
+        let mut ser = Ser {
+            file,
+            width: le_u32(&header, 26),
+            height: le_u32(&header, 30),
+            color_id: le_u32(&header, 18),
+            bit_depth: le_u32(&header, 34),
+            frame_count: le_u32(&header, 38) as usize,
+            timestamps: Vec::new(),
+        };
+        assert!((1..=16).contains(&ser.bit_depth), "unsupported bit depth {} of {}", ser.bit_depth, path.display());
+        let trailer = Ser::HEADER_SIZE + (ser.frame_count * ser.frame_size()) as u64;
+        let len = ser.file.get_ref().metadata().unwrap().len();
+        if len >= trailer + 8 * ser.frame_count as u64 {
+            let data = read_bytes(&mut ser.file, trailer, 8 * ser.frame_count);
+            ser.timestamps = (0..ser.frame_count).map(|i| le_i64(&data, 8 * i)).collect();
+        }
+        ser
+    }
+
+    fn channels(&self) -> usize {
+        if matches!(self.color_id, 100 | 101) { 3 } else { 1 }
+    }
+
+    fn frame_size(&self) -> usize {
+        let bytes_per_sample = if self.bit_depth > 8 { 2 } else { 1 };
+        self.width as usize * self.height as usize * self.channels() * bytes_per_sample
+    }
+
+    fn frame(&mut self, index: usize) -> DynamicImage {
+        let size = self.frame_size();
+        let data = read_bytes(&mut self.file, Ser::HEADER_SIZE + (index * size) as u64, size);
+        let (width, height) = (self.width, self.height);
+        // 16 bit samples are little endian in practice, independent of the endianness field
+        let mut samples: Vec<u16> = if self.bit_depth > 8 {
+            data.chunks(2).map(|sample| u16::from_le_bytes([sample[0], sample[1]]) << (16 - self.bit_depth)).collect()
+        } else {
+            data.iter().map(|&sample| (sample as u16) << 8).collect()
+        };
+        if self.color_id == 101 {
+            for bgr in samples.chunks_mut(3) {
+                bgr.swap(0, 2);
+            }
+        }
+        if self.channels() == 3 {
+            DynamicImage::ImageRgb16(ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, samples).unwrap())
+        } else {
+            DynamicImage::ImageLuma16(ImageBuffer::<Luma<u16>, _>::from_raw(width, height, samples).unwrap())
+        }
+    }
+}
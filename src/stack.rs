@@ -1,90 +1,102 @@
 use std::sync::atomic::{AtomicU32, Ordering};
-use image::Rgb64FImage;
-use rayon::iter::{ParallelIterator, IntoParallelRefIterator};
-use crate::{Colorspace, CommonArgs, helpers, processing, rejection, Stack};
+use image::{Luma, Rgb};
+use rayon::iter::ParallelIterator;
+use crate::{CommonArgs, helpers, processing, RegistrationMethod, rejection, source, Stack};
+use crate::derotation::Derotation;
+use crate::helpers::{FloatImage, FloatPixel};
+use crate::loader::Loader;
 use crate::register::AkazeRegistration;
 
 pub fn stack(common: CommonArgs, stack: Stack) {
-    let CommonArgs { colorspace, num_files, skip_files } = common;
-    let Stack { registration_input, rejection, preprocessing, postprocessing, outfile_prefix } = stack;
+    if common.mono {
+        stack_generic::<Luma<f64>>(common, stack)
+    } else {
+        stack_generic::<Rgb<f64>>(common, stack)
+    }
+}
+
+fn stack_generic<P: FloatPixel>(common: CommonArgs, stack: Stack) {
+    let CommonArgs { colorspace, num_files, skip_files, mono: _, max_memory: _, prefetch: _ } = common;
+    let Stack { registration_input, rejection, rejection_policy, preprocessing, postprocessing, methods, derotate, pole_angle, epoch, outfile_prefix } = stack;
 
     let registration = helpers::load_registration(registration_input);
     let reference_image = &registration.images[registration.reference_image];
-    let ref_img = helpers::load_image(&reference_image.image, Colorspace::Srgb);
-    let width = ref_img.width();
-    let height = ref_img.height();
+    let (width, height) = source::dimensions(&reference_image.image);
 
     println!("Starting rejection");
     let images = helpers::clamp_slice(&registration.images, skip_files, num_files);
-    let images = rejection::reject(&registration, images.to_owned(), &rejection);
+    let images = rejection::reject(&registration, images.to_owned(), &rejection, rejection_policy);
     println!("Rejection finished");
+    let derotation = derotate.and_then(|planet| Derotation::new(planet, pole_angle, epoch, &images));
 
-    let creation_fn = || {
-        let img = Rgb64FImage::new(width, height);
-        (img.clone(), img.clone(), img.clone())
-    };
+    // skip methods without any offsets, e.g. akaze if it wasn't run or fused for old registrations
+    let methods: Vec<_> = methods.into_iter()
+        .filter(|&method| {
+            let available = images.iter().any(|reg| reg.offset(reference_image, method).is_some());
+            if !available {
+                println!("no {} registrations, skipping {} stack", method.name(), method.name());
+            }
+            available
+        }).collect();
+    let accumulators = || vec![FloatImage::<P>::new(width, height); methods.len()];
+    // per thread: the frame, an intermediate processing result, the derotated frame and the accumulators
+    let loader = Loader::new::<P>(&common, width, height, 2 + derotation.is_some() as usize + methods.len(), 0);
 
     println!("Starting Stacking");
     let counter = AtomicU32::new(0);
-    let (mut akaze, mut sod, mut aba) = images.par_iter()
-        .map(|reg| (helpers::load_image(&reg.image, colorspace), reg))
-        .fold(creation_fn, |(mut akaze, mut sod, mut aba), (mut image, reg)| {
+    let mut stacked = loader.par_load(images, |reg| &reg.image, |frames| frames
+        .fold(accumulators, |mut stacked, (reg, mut image)| {
             let count = counter.fetch_add(1, Ordering::Relaxed);
             if count % 50 == 0 {
                 println!("{count}");
             }
 
             processing::process(&mut image, num_files, &preprocessing);
+            if let Some(derotation) = &derotation {
+                image = derotation.derotate(&image, &reg);
+            }
 
-            // akaze
-            match reg.akaze {
-                Some(AkazeRegistration::Offset(dx, dy)) => {
-                    stack_into(&mut akaze, &image, dx as i32, dy as i32);
+            if matches!(reg.akaze, Some(AkazeRegistration::Rejected)) && methods.contains(&RegistrationMethod::Akaze) {
+                println!("rejected akaze {count:05}");
+            }
+            for (&method, buf) in methods.iter().zip(&mut stacked) {
+                if let Some((dx, dy)) = reg.offset(reference_image, method) {
+                    stack_into(buf, &image, dx, dy);
                 }
-                Some(AkazeRegistration::Rejected) => println!("rejected akaze {count:05}"),
-                None => (),
             }
-
-            // sod
-            let (dx, dy) = reg.sod.offset(&reference_image.sod);
-            stack_into(&mut sod, &image, dx, dy);
-
-            // aba
-            let (dx, dy) = reg.aba.offset(&reference_image.aba);
-            stack_into(&mut aba, &image, dx, dy);
-            (akaze, sod, aba)
-        }).reduce(creation_fn, |(mut akaze1, mut sod1, mut aba1), (akaze2, sod2, aba2)| {
-            stack_into(&mut akaze1, &akaze2, 0, 0);
-            stack_into(&mut sod1, &sod2, 0, 0);
-            stack_into(&mut aba1, &aba2, 0, 0);
-            (akaze1, sod1, aba1)
-        });
+            stacked
+        }).reduce_with(|mut stacked1, stacked2| {
+            for (buf1, buf2) in stacked1.iter_mut().zip(&stacked2) {
+                stack_into(buf1, buf2, 0, 0);
+            }
+            stacked1
+        })).expect("no images left to stack after rejection");
 
     println!("Stacking completed");
     println!("Starting postprocessing");
-    processing::process(&mut akaze, num_files, &postprocessing);
-    processing::process(&mut sod, num_files, &postprocessing);
-    processing::process(&mut aba, num_files, &postprocessing);
+    for buf in &mut stacked {
+        processing::process(buf, num_files, &postprocessing);
+    }
 
     println!("Processing completed");
     println!("Saving Image");
-    helpers::save_image(akaze, helpers::path_with_suffix(&outfile_prefix, "akaze.png"), colorspace);
-    helpers::save_image(sod, helpers::path_with_suffix(&outfile_prefix, "sod.png"), colorspace);
-    helpers::save_image(aba, helpers::path_with_suffix(&outfile_prefix, "aba.png"), colorspace);
+    for (method, buf) in methods.into_iter().zip(stacked) {
+        helpers::save_image(buf, helpers::path_with_suffix(&outfile_prefix, &format!("{}.png", method.name())), colorspace);
+    }
     println!("Saving completed");
     println!("Done");
 }
 
-pub fn stack_into(buf: &mut Rgb64FImage, img: &Rgb64FImage, dx: i32, dy: i32) {
-    for (x, y, &pixel) in img.enumerate_pixels() {
+pub fn stack_into<P: FloatPixel>(buf: &mut FloatImage<P>, img: &FloatImage<P>, dx: i32, dy: i32) {
+    for (x, y, pixel) in img.enumerate_pixels() {
         let bufx = (x as i32 + dx) as u32;
         let bufy = (y as i32 + dy) as u32;
         let bufpx = match buf.get_pixel_mut_checked(bufx, bufy) {
             Some(px) => px,
             None => continue,
         };
-        bufpx.0[0] += pixel.0[0];
-        bufpx.0[1] += pixel.0[1];
-        bufpx.0[2] += pixel.0[2];
+        for (value, source) in bufpx.channels_mut().iter_mut().zip(pixel.channels()) {
+            *value += source;
+        }
     }
-}
\ No newline at end of file
+}
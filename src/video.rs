@@ -1,23 +1,44 @@
-use std::fs::File;
-use image::{EncodableLayout, RgbImage};
-use image::buffer::ConvertBuffer;
-use minimp4::Mp4Muxer;
-use openh264::encoder::{Encoder, EncoderConfig};
-use openh264::formats::RBGYUVConverter;
-use crate::{CommonArgs, helpers, processing, Processing, rejection, Video};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use image::{GenericImage, imageops, Luma, Rgb};
+use image::imageops::FilterType;
+use crate::{CommonArgs, font, helpers, processing, Processing, RegistrationMethod, rejection, Video};
+use crate::derotation::Derotation;
+use crate::encoding::{VideoFormat, VideoWriter};
+use crate::helpers::{FloatImage, FloatPixel};
+use crate::loader::Loader;
+use crate::register::ImageRegistration;
+use crate::rejection::FrameReport;
 
 pub fn video(common: CommonArgs, video: Video) {
-    let CommonArgs { colorspace, num_files, skip_files } = common;
-    let Video { registration_input, rejection, processing, outfile_prefix } = video;
+    if common.mono {
+        video_generic::<Luma<f64>>(common, video)
+    } else {
+        video_generic::<Rgb<f64>>(common, video)
+    }
+}
+
+fn video_generic<P: FloatPixel>(common: CommonArgs, video: Video) {
+    let CommonArgs { colorspace, num_files, skip_files, mono: _, max_memory: _, prefetch: _ } = common;
+    let Video { registration_input, rejection, rejection_policy, processing, methods, no_orig, fps, bitrate, scale, crop, grid, overlay, high_bit_depth, derotate, pole_angle, epoch, outfile_prefix } = video;
 
     let registration = helpers::load_registration(registration_input);
     let reference = &registration.images[registration.reference_image];
-    let reference_image = helpers::load_image(&reference.image, colorspace);
+    let reference_image = helpers::load_image::<P, _>(&reference.image, colorspace);
     let width = reference_image.width();
     let height = reference_image.height();
 
-    let images = helpers::clamp_slice(&registration.images, skip_files, num_files);
-    let images = rejection::reject(&registration, images.to_owned(), &rejection);
+    let images = helpers::clamp_slice(&registration.images, skip_files, num_files).to_owned();
+    let (accepted_images, report) = rejection::reject_with_report(&registration, images.clone(), &rejection, rejection_policy);
+    rejection::print_summary(&report);
+    // the grid shows all frames with and without rejection side by side
+    let frame_reports: HashMap<&Path, (usize, &FrameReport)> = report.frames.iter().enumerate()
+        .map(|(i, frame)| (frame.image.as_path(), (skip_files + i, frame)))
+        .collect();
+    let images = if grid { images } else { accepted_images };
+    let derotation = derotate.and_then(|planet| Derotation::new(planet, pole_angle, epoch, &images));
 
     // replace maxscale with maxscale_fixed based on first image
     let processing: Vec<_> = processing.into_iter()
@@ -26,55 +47,196 @@ pub fn video(common: CommonArgs, video: Video) {
             p => p,
         }).collect();
 
-    let config = EncoderConfig::new(width, height);
-    let mut encoder_orig = Encoder::with_config(config).unwrap();
-    let mut encoder_akaze = Encoder::with_config(config).unwrap();
-    let mut encoder_sod = Encoder::with_config(config).unwrap();
-    let mut encoder_aba = Encoder::with_config(config).unwrap();
-
-    let mut buf_orig = Vec::new();
-    let mut buf_akaze = Vec::new();
-    let mut buf_sod = Vec::new();
-    let mut buf_aba = Vec::new();
-
-    let encode_into = |encoder: &mut Encoder, buf: &mut Vec<u8>, frame: &RgbImage| {
-        let mut yuv = RBGYUVConverter::new(width as usize, height as usize);
-        yuv.convert(frame.as_bytes());
-        // Encode YUV into H.264.
-        let bitstream = encoder.encode(&yuv).unwrap();
-        bitstream.write_vec(buf);
+    // H.264 needs even dimensions
+    let even = |value: u32| (value / 2 * 2).max(2);
+    let (left, top, crop_width, crop_height) = helpers::crop_region(crop, reference, width, height);
+    let (crop_width, crop_height) = (even(crop_width).min(width / 2 * 2), even(crop_height).min(height / 2 * 2));
+    assert!(crop_width >= 2 && crop_height >= 2, "frames of {width}x{height} are too small for a video");
+    // rounding up to even dimensions must not extend the crop beyond the frame, as the encoders expect all
+    // frames to have the same size
+    let (left, top) = (left.min(width - crop_width), top.min(height - crop_height));
+    let out_width = even((crop_width as f32 * scale).round() as u32);
+    let out_height = even((crop_height as f32 * scale).round() as u32);
+    let finish_frame = |frame: &FloatImage<P>| {
+        let frame = imageops::crop_imm(frame, left, top, crop_width, crop_height).to_image();
+        if (out_width, out_height) == (crop_width, crop_height) {
+            frame
+        } else {
+            imageops::resize(&frame, out_width, out_height, FilterType::Triangle)
+        }
     };
 
-    for (i, reg) in images.iter().enumerate() {
-        if i % 50 == 0 {
-            println!("{i}");
-        }
-        let mut image = helpers::load_image(&reg.image, colorspace);
+    // `None` is the video of the unaligned frames
+    let mut variants: Vec<Variant> = (!no_orig).then_some(Variant { method: None, rejected: false }).into_iter()
+        .chain(methods.iter().map(|&method| Variant { method: Some(method), rejected: false }))
+        .collect();
+    if grid && !rejection.is_empty() {
+        variants.extend(methods.iter().map(|&method| Variant { method: Some(method), rejected: true }));
+    }
+    let columns = (variants.len() as f64).sqrt().ceil().max(1.) as u32;
+    let rows = (variants.len() as u32).div_ceil(columns).max(1);
+    let (video_width, video_height) = if grid { (columns * out_width, rows * out_height) } else { (out_width, out_height) };
+
+    let (format, outfile_prefix) = VideoFormat::from_path(&outfile_prefix);
+    // (file name suffix, number of frames) of each video
+    let outputs: Vec<(String, usize)> = if grid {
+        vec![("grid".to_string(), images.len())]
+    } else {
+        variants.retain(|variant| {
+            let has_frames = images.iter().any(|reg| variant.offset(reg, reference, true).is_some());
+            if !has_frames {
+                println!("no {} frames, skipping {} video", variant.label(), variant.label());
+            }
+            has_frames
+        });
+        variants.iter().map(|variant| {
+            let num_frames = images.iter().filter(|reg| variant.offset(reg, reference, true).is_some()).count();
+            (variant.label(), num_frames)
+        }).collect()
+    };
+    // last frame of each variant, held in the grid while a variant has no frame
+    let mut last_frames: Vec<Option<FloatImage<P>>> = vec![None; variants.len()];
+    let label_scale = (out_height / 240).max(1);
+
+    // per thread: the frame, an intermediate processing result and the derotated frame, with the frames of
+    // all variants as result; besides the reference, each encoder holds the frames in its channel and the
+    // one being written, and the grid the last frame of each variant and the frame being composed
+    let encoder_frames = if grid { 5 * variants.len() } else { 3 * variants.len() };
+    let loader = Loader::new_ordered::<P>(&common, width, height, 2 + derotation.is_some() as usize, variants.len(), 1 + encoder_frames);
+    let process_frame = |reg: ImageRegistration, mut image: FloatImage<P>| {
         processing::process(&mut image, num_files, &processing);
-        let image: RgbImage = image.convert();
-        let frame_sod = helpers::offset_image(&image, reg.sod.offset(&reference.sod));
-        let frame_aba = helpers::offset_image(&image, reg.aba.offset(&reference.aba));
-        encode_into(&mut encoder_orig, &mut buf_orig, &image);
-        if let Some(akaze) = &reg.akaze {
-            let frame = helpers::offset_image(&image, akaze.offset());
-            encode_into(&mut encoder_akaze, &mut buf_akaze, &frame);
+        if let Some(derotation) = &derotation {
+            image = derotation.derotate(&image, &reg);
+        }
+        if overlay {
+            helpers::draw_object(&mut image, reg.sod);
+            helpers::draw_cross(&mut image, (reg.aba.middlex, reg.aba.middley));
+            if let Some(disk) = reg.disk {
+                helpers::draw_disk(&mut image, disk);
+            }
+        }
+        let (index, frame_report) = frame_reports[reg.image.as_path()];
+        let annotation = overlay.then(|| annotation(index, &reg, reference, frame_report, &report.rules));
+        variants.iter().map(|variant| {
+            variant.offset(&reg, reference, frame_report.accepted).map(|offset| {
+                let mut frame = finish_frame(&helpers::offset_image(&image, offset));
+                if let Some(annotation) = &annotation {
+                    annotate(&mut frame, annotation, label_scale);
+                }
+                frame
+            })
+        }).collect::<Vec<_>>()
+    };
 
+    // each video is encoded on its own thread, receiving the frames in order
+    thread::scope(|s| {
+        let encoders: Vec<_> = outputs.into_iter().map(|(name, num_frames)| {
+            let (tx, rx) = mpsc::sync_channel::<FloatImage<P>>(2);
+            let path = helpers::path_with_suffix(&outfile_prefix, &format!("{name}.{}", format.extension()));
+            s.spawn(move || {
+                let mut writer = VideoWriter::new(format, path.clone(), (video_width, video_height), fps, bitrate, high_bit_depth, num_frames as u32);
+                for frame in rx {
+                    writer.write(&frame);
+                }
+                writer.finish().unwrap_or_else(|e| panic!("error writing {}: {e}", path.display()));
+            });
+            tx
+        }).collect();
+
+        let mut count = 0;
+        loader.par_map_ordered(images, |reg| &reg.image, process_frame, |frames| {
+            if count % 50 == 0 {
+                println!("{count}");
+            }
+            count += 1;
+            if !grid {
+                for (encoder, frame) in encoders.iter().zip(frames) {
+                    if let Some(frame) = frame {
+                        encoder.send(frame).unwrap();
+                    }
+                }
+                return;
+            }
+
+            let mut composed = FloatImage::<P>::new(video_width, video_height);
+            for (((index, variant), frame), last_frame) in variants.iter().enumerate().zip(frames).zip(&mut last_frames) {
+                if frame.is_some() {
+                    *last_frame = frame;
+                }
+                let x = index as u32 % columns * out_width;
+                let y = index as u32 / columns * out_height;
+                if let Some(frame) = last_frame {
+                    composed.copy_from(frame, x, y).unwrap();
+                }
+                font::draw_label(&mut composed, (x, y), label_scale, &variant.label());
+            }
+            encoders[0].send(composed).unwrap();
+        });
+    });
+}
+
+/// Video of the frames aligned by a registration method, or of the unaligned frames
+#[derive(Debug, Copy, Clone)]
+struct Variant {
+    method: Option<RegistrationMethod>,
+    /// rejected frames are skipped
+    rejected: bool,
+}
+
+impl Variant {
+    /// Offset to align the frame with, `None` if the variant has no frame for it
+    fn offset(&self, reg: &ImageRegistration, reference: &ImageRegistration, accepted: bool) -> Option<(i32, i32)> {
+        match self {
+            Variant { rejected: true, .. } if !accepted => None,
+            Variant { method: None, .. } => Some((0, 0)),
+            Variant { method: Some(method), .. } => reg.offset(reference, *method),
         }
-        encode_into(&mut encoder_sod, &mut buf_sod, &frame_sod);
-        encode_into(&mut encoder_aba, &mut buf_aba, &frame_aba);
     }
 
-    let save_buf = |name: &str, data: &[u8]| {
-        let file_name = helpers::path_with_suffix(&outfile_prefix, &format!("{}.mp4", name));
-        let file = File::create(file_name).unwrap();
-        let mut mp4muxer = Mp4Muxer::new(file);
-        mp4muxer.init_video(width as i32, height as i32, false, name);
-        mp4muxer.write_video_with_fps(&data, 25);
-        mp4muxer.close();
-    };
+    fn label(&self) -> String {
+        let name = self.method.map_or("orig", RegistrationMethod::name);
+        if self.rejected {
+            format!("{name}_rejected")
+        } else {
+            name.to_string()
+        }
+    }
+}
 
-    save_buf("orig", &buf_orig);
-    save_buf("akaze", &buf_akaze);
-    save_buf("sod", &buf_sod);
-    save_buf("aba", &buf_aba);
-}
\ No newline at end of file
+/// Lines describing the registration and rejection of a frame
+fn annotation(index: usize, reg: &ImageRegistration, reference: &ImageRegistration, report: &FrameReport, rules: &[String]) -> Vec<String> {
+    let name = reg.image.file_name().unwrap_or_default().to_string_lossy();
+    let mut lines = vec![format!("#{index} {name}")];
+    let methods = [RegistrationMethod::Akaze, RegistrationMethod::Sod, RegistrationMethod::Aba, RegistrationMethod::Disk];
+    for (method, offset) in methods.iter().zip(reg.method_offsets(reference)) {
+        lines.push(match offset {
+            Some((dx, dy)) => format!("{} {dx:.1},{dy:.1}", method.name()),
+            None => format!("{} n/a", method.name()),
+        });
+    }
+    let failed: Vec<_> = rules.iter().zip(&report.verdicts)
+        .filter(|(_, verdict)| !verdict.accepted)
+        .map(|(rule, _)| rule.as_str())
+        .collect();
+    lines.push(match (report.accepted, failed.is_empty()) {
+        (true, true) => "accepted".to_string(),
+        (true, false) => format!("accepted, failed {}", failed.join(" ")),
+        (false, _) => format!("rejected by {}", failed.join(" ")),
+    });
+    if let Some(statistics) = reg.statistics {
+        let fwhm = statistics.fwhm.map_or("n/a".to_string(), |fwhm| format!("{fwhm:.2}"));
+        lines.push(format!("stars {} fwhm {fwhm}", statistics.stars));
+        lines.push(format!("brightness {:.3} background {:.3}", statistics.brightness, statistics.background));
+        lines.push(format!("sharpness {:.2e}", statistics.sharpness));
+    }
+    lines
+}
+
+/// Draw the annotation lines at the bottom left of the frame
+fn annotate<P: FloatPixel>(frame: &mut FloatImage<P>, lines: &[String], scale: u32) {
+    let line_height = (font::GLYPH_HEIGHT + 2) * scale;
+    let top = frame.height().saturating_sub(lines.len() as u32 * line_height);
+    for (i, line) in lines.iter().enumerate() {
+        font::draw_label(frame, (0, top + i as u32 * line_height), scale, line);
+    }
+}
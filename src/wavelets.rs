@@ -0,0 +1,112 @@
+use image::ImageBuffer;
+use rayon::prelude::*;
+use crate::helpers::{self, FloatImage, FloatPixel, Luma64FImage};
+
+/// B3-spline used as scaling function of the à trous transform
+const B3: [f64; 5] = [1. / 16., 4. / 16., 6. / 16., 4. / 16., 1. / 16.];
+/// Standard deviation of the detail layers (finest first) of unit gaussian noise, roughly halving per layer
+const NOISE_FACTORS: [f64; 6] = [0.890, 0.201, 0.086, 0.041, 0.020, 0.010];
+
+/// Sharpen the image by amplifying the detail layers of an à trous wavelet decomposition.
+///
+/// `layers` contains `(gain, denoise)` for each layer, starting with the finest one.
+/// The denoise value is the soft-threshold in multiples of the noise of the layer, estimated from the finest
+/// layer, as coarser layers are dominated by structure.
+pub fn sharpen<P: FloatPixel>(buf: &mut FloatImage<P>, layers: &[(f64, f64)]) {
+    let mut channels = helpers::split_channels(buf);
+    channels.par_iter_mut().for_each(|channel| sharpen_channel(channel, layers));
+    *buf = helpers::merge_channels(&channels);
+}
+
+pub fn sharpen_channel(channel: &mut Luma64FImage, layers: &[(f64, f64)]) {
+    let (details, mut residual) = decompose(channel, layers.len());
+    let noise = match details.first() {
+        Some(finest) if layers.iter().any(|&(_, denoise)| denoise > 0.) => noise_sigma(finest) / NOISE_FACTORS[0],
+        _ => 0.,
+    };
+    for (layer, (mut detail, &(gain, denoise))) in details.into_iter().zip(layers).enumerate() {
+        if denoise > 0. {
+            soft_threshold(&mut detail, denoise * noise * noise_factor(layer));
+        }
+        for (res, &d) in residual.iter_mut().zip(detail.iter()) {
+            *res += gain * d;
+        }
+    }
+    *channel = residual;
+}
+
+/// Decompose the channel into `num_layers` detail layers (finest first) and the residual.
+///
+/// Summing all detail layers and the residual results in the original channel.
+pub fn decompose(channel: &Luma64FImage, num_layers: usize) -> (Vec<Luma64FImage>, Luma64FImage) {
+    let mut details = Vec::with_capacity(num_layers);
+    let mut current = channel.clone();
+    for layer in 0..num_layers {
+        let smoothed = smooth(&current, 1 << layer);
+        for (cur, &s) in current.iter_mut().zip(smoothed.iter()) {
+            *cur -= s;
+        }
+        details.push(current);
+        current = smoothed;
+    }
+    (details, current)
+}
+
+/// Separable B3-spline convolution with holes of size `step`, mirroring at the borders
+fn smooth(channel: &Luma64FImage, step: usize) -> Luma64FImage {
+    let width = channel.width() as usize;
+    let height = channel.height() as usize;
+    let step = step as isize;
+
+    let mut rows = vec![0.; width * height];
+    rows.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let src = &channel.as_raw()[y * width..(y + 1) * width];
+        for (x, out) in row.iter_mut().enumerate() {
+            *out = B3.iter().enumerate()
+                .map(|(k, w)| w * src[mirror(x as isize + (k as isize - 2) * step, width)])
+                .sum();
+        }
+    });
+
+    let mut res = vec![0.; width * height];
+    res.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, out) in row.iter_mut().enumerate() {
+            *out = B3.iter().enumerate()
+                .map(|(k, w)| w * rows[mirror(y as isize + (k as isize - 2) * step, height) * width + x])
+                .sum();
+        }
+    });
+    ImageBuffer::from_raw(width as u32, height as u32, res).unwrap()
+}
+
+fn mirror(i: isize, len: usize) -> usize {
+    let max = len as isize - 1;
+    let i = i.abs();
+    let i = if i > max { 2 * max - i } else { i };
+    i.max(0).min(max) as usize
+}
+
+/// Noise of the given detail layer relative to the noise of the image
+fn noise_factor(layer: usize) -> f64 {
+    match NOISE_FACTORS.get(layer) {
+        Some(&factor) => factor,
+        None => NOISE_FACTORS[NOISE_FACTORS.len() - 1] / (1 << (layer + 1 - NOISE_FACTORS.len())) as f64,
+    }
+}
+
+/// Estimate the noise of a detail layer via the median absolute deviation
+pub fn noise_sigma(layer: &Luma64FImage) -> f64 {
+    let mut abs: Vec<f64> = layer.iter().map(|v| v.abs()).collect();
+    if abs.is_empty() {
+        return 0.;
+    }
+    let mid = abs.len() / 2;
+    let (_, median, _) = abs.select_nth_unstable_by(mid, f64::total_cmp);
+    *median / 0.6745
+}
+
+pub fn soft_threshold(layer: &mut Luma64FImage, threshold: f64) {
+    for value in layer.iter_mut() {
+        *value = value.signum() * (value.abs() - threshold).max(0.);
+    }
+}